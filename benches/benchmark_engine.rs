@@ -1,10 +1,9 @@
 use criterion::{ criterion_group, criterion_main, Criterion };
-use kvs::engines::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::engines::{Durability, KvStore, KvsEngine, SledKvsEngine};
 use kvs::error::Result;
-use kvs::utils::SLED_FILE_NAME;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use std::path::PathBuf;
+use tempfile::TempDir;
 
 fn create_random_values(n: u64) -> Vec<(String, String)> {
     let byte_length_range = 1..100_000;
@@ -37,7 +36,7 @@ fn create_random_values(n: u64) -> Vec<(String, String)> {
     vec
 }
 
-fn kvs_set(key_value_pairs: Vec<(String, String)>, kv_store: &mut KvStore) -> Result<()> {
+fn kvs_set(key_value_pairs: Vec<(String, String)>, kv_store: &KvStore) -> Result<()> {
     for (key, value) in key_value_pairs.into_iter() {
         kv_store.set(key, value)?;
     }
@@ -45,21 +44,15 @@ fn kvs_set(key_value_pairs: Vec<(String, String)>, kv_store: &mut KvStore) -> Re
     Ok(())
 }
 
-fn kvs_get(key_value_pairs: Vec<(String, String)>, kv_store: &mut KvStore) -> Result<()> {
-    // let path = PathBuf::from("");
-    // let mut kv_store = KvStore::open(path)?;
-
-    println!("inside kvs_get");
+fn kvs_get(key_value_pairs: Vec<(String, String)>, kv_store: &KvStore) -> Result<()> {
     for (key, _value) in key_value_pairs.into_iter() {
-        println!("starting iteration");
         kv_store.get(key)?;
-        println!("kvs_get iteration complete");
     }
 
     Ok(())
 }
 
-fn sled_set(key_value_pairs: Vec<(String, String)>, sled_engine: &mut SledKvsEngine) -> Result<()> {
+fn sled_set(key_value_pairs: Vec<(String, String)>, sled_engine: &SledKvsEngine) -> Result<()> {
     for (key, value) in key_value_pairs.into_iter() {
         sled_engine.set(key, value)?;
     }
@@ -67,7 +60,7 @@ fn sled_set(key_value_pairs: Vec<(String, String)>, sled_engine: &mut SledKvsEng
     Ok(())
 }
 
-fn sled_get(key_value_pairs: Vec<(String, String)>, sled_engine: &mut SledKvsEngine) -> Result<()> {
+fn sled_get(key_value_pairs: Vec<(String, String)>, sled_engine: &SledKvsEngine) -> Result<()> {
     for (key, _value) in key_value_pairs.into_iter() {
         sled_engine.get(key)?;
     }
@@ -77,34 +70,32 @@ fn sled_get(key_value_pairs: Vec<(String, String)>, sled_engine: &mut SledKvsEng
 
 pub fn criterion_benchmark(c: &mut Criterion) {
     let key_value_pairs = create_random_values(100);
-    let path = PathBuf::from("");
-    println!("path opened");
-    let mut kv_store = KvStore::open(path).unwrap();
+    //Each engine gets a directory of its own, since opening one records it as the directory's engine
+    let kvs_dir = TempDir::new().unwrap();
+    let kv_store = KvStore::open(kvs_dir.path()).unwrap();
 
     println!("starting group kvs");
 
-    let sled_db = SledKvsEngine::open(SLED_FILE_NAME).unwrap();
-
-    let mut sled_engine = SledKvsEngine {
-        directory_path: PathBuf::from(SLED_FILE_NAME),
-        sled_db: sled_db,
-    };
+    let sled_dir = TempDir::new().unwrap();
+    let sled_engine = SledKvsEngine::open(sled_dir.path(), Durability::default()).unwrap();
 
     let mut group = c.benchmark_group("kvs");
     group.sample_size(10);
     group.bench_function("kvs set 10", |b| {
-        b.iter(|| kvs_set(key_value_pairs.clone(), &mut kv_store))
+        b.iter(|| kvs_set(key_value_pairs.clone(), &kv_store))
+    });
+    group.bench_function("kvs get 10", |b| {
+        b.iter(|| kvs_get(key_value_pairs.clone(), &kv_store))
     });
-    // group.bench_function("kvs get 10", |b| b.iter(|| kvs_get(key_value_pairs.clone(),&mut kv_store)));
     group.finish();
 
     let mut sled_group = c.benchmark_group("sled");
     sled_group.sample_size(100);
     sled_group.bench_function("sled set 100", |b| {
-        b.iter(|| sled_set(key_value_pairs.clone(), &mut sled_engine))
+        b.iter(|| sled_set(key_value_pairs.clone(), &sled_engine))
     });
     sled_group.bench_function("sled get 100", |b| {
-        b.iter(|| sled_get(key_value_pairs.clone(), &mut sled_engine))
+        b.iter(|| sled_get(key_value_pairs.clone(), &sled_engine))
     });
     sled_group.finish();
 }
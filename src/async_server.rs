@@ -0,0 +1,88 @@
+use crate::engines::{AsyncKvsEngine, KvsEngine};
+use crate::error::{KvsError, Result};
+use crate::server::{KvsServer, ServerOptions};
+use crate::protocol::{read_next_frame_async, write_frame_async, Request, Response};
+use crate::utils::{KVS_CODE, SLED_CODE};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+use tracing::{error, info, warn};
+
+///Server handling every connection as a task on the tokio runtime it is started on, so idle clients cost no thread
+pub struct AsyncKvsServer {}
+
+impl AsyncKvsServer {
+    ///Open the engine and serve requests until accepting a connection fails. The pool settings in the options are unused
+    pub async fn route_request(ip_string: String, engine: String, options: ServerOptions) -> Result<()> {
+        match engine.as_bytes() {
+            KVS_CODE => {
+                let kv_store = KvsServer::open_kvs(&options)?;
+                let engine = AsyncKvsEngine::new(kv_store);
+                tokio::spawn(AsyncKvsServer::sweep(engine.clone(), options.sweep_interval));
+                AsyncKvsServer::serve(ip_string, engine).await
+            }
+            SLED_CODE => {
+                let sled_engine = KvsServer::open_sled(&options)?;
+                let engine = AsyncKvsEngine::new(sled_engine);
+                tokio::spawn(AsyncKvsServer::sweep(engine.clone(), options.sweep_interval));
+                AsyncKvsServer::serve(ip_string, engine).await
+            }
+            _ => Err(KvsError::CommandError("Engine not found".to_string())),
+        }
+    }
+
+    ///Accept connections and handle each one on its own task
+    pub async fn serve<E: KvsEngine>(ip_string: String, engine: AsyncKvsEngine<E>) -> Result<()> {
+        let listener = TcpListener::bind(ip_string).await?;
+
+        loop {
+            let (stream, _address) = listener.accept().await?;
+            let engine = engine.clone();
+
+            tokio::spawn(async move {
+                if let Err(error) = AsyncKvsServer::handle_request(stream, &engine).await {
+                    error!("Unable to handle request: {}", error);
+                }
+            });
+        }
+    }
+
+    ///Remove expired keys from the engine every interval, for as long as the runtime runs
+    async fn sweep<E: KvsEngine>(engine: AsyncKvsEngine<E>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let result = engine
+                .run(|engine| {
+                    KvsServer::sweep(engine);
+                    Ok(())
+                })
+                .await;
+
+            if let Err(error) = result {
+                warn!("Unable to remove expired keys: {}", error);
+            }
+        }
+    }
+
+    ///Answer requests on a connection until the client closes it
+    async fn handle_request<E: KvsEngine>(mut stream: TcpStream, engine: &AsyncKvsEngine<E>) -> Result<()> {
+        info!("Connection initiated");
+
+        stream.set_nodelay(true)?;
+
+        loop {
+            let response = match read_next_frame_async::<Request>(&mut stream).await {
+                Ok(Some(request)) => engine
+                    .run(move |engine| Ok(KvsServer::respond(request, engine)))
+                    .await
+                    .unwrap_or_else(|error| Response::from_error(&error)),
+                Ok(None) => return Ok(()),
+                //The connection failed, so there is no one to answer
+                Err(error @ KvsError::Io(_)) => return Err(error),
+                Err(error) => Response::from_error(&error),
+            };
+
+            write_frame_async(&mut stream, &response).await?;
+        }
+    }
+}
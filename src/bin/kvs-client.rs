@@ -1,7 +1,9 @@
 use clap::Parser;
 use kvs::client::KvsClient;
-use kvs::error::Result;
+use kvs::engines::CasOutcome;
+use kvs::error::{KvsError, Result};
 use std::process;
+use std::time::Duration;
 
 const DEFAULT_IP_ADDRESS: &str = "127.0.0.1:4000";
 
@@ -23,6 +25,9 @@ enum Command {
         ///The value to be set
         #[clap(required = true)]
         value: String,
+        ///Seconds until the key expires. Without it the key never does
+        #[clap(long)]
+        ttl: Option<u64>,
         ///Optional IP:PORT target
         #[clap(short, long, default_value_t = String::from(DEFAULT_IP_ADDRESS))]
         addr: String,
@@ -35,6 +40,14 @@ enum Command {
         #[clap(short, long, default_value_t = String::from(DEFAULT_IP_ADDRESS))]
         addr: String,
     },
+    ///Print the seconds left before a key expires
+    Ttl {
+        #[clap(required = true)]
+        key: String,
+        ///Optional IP:PORT target
+        #[clap(short, long, default_value_t = String::from(DEFAULT_IP_ADDRESS))]
+        addr: String,
+    },
     ///Removes a given key
     Rm {
         #[clap(required = true)]
@@ -43,6 +56,35 @@ enum Command {
         #[clap(short, long, default_value_t = String::from(DEFAULT_IP_ADDRESS))]
         addr: String,
     },
+    ///Set a key to a new value only if it holds the expected one, exiting with a non-zero code if it does not
+    Cas {
+        #[clap(required = true)]
+        key: String,
+        ///The value the key must hold. Without it the key must not exist
+        #[clap(long)]
+        expected: Option<String>,
+        ///The value to set. Without it the key is removed
+        #[clap(long)]
+        new: Option<String>,
+        ///Optional IP:PORT target
+        #[clap(short, long, default_value_t = String::from(DEFAULT_IP_ADDRESS))]
+        addr: String,
+    },
+    ///List keys in order with their values, one tab-separated pair per line
+    Scan {
+        ///Only list keys starting with this prefix
+        #[clap(long, default_value_t = String::new())]
+        prefix: String,
+        ///Start after this key, the last one listed by the previous page
+        #[clap(long)]
+        start_after: Option<String>,
+        ///List at most this many keys
+        #[clap(short, long)]
+        limit: Option<u64>,
+        ///Optional IP:PORT target
+        #[clap(short, long, default_value_t = String::from(DEFAULT_IP_ADDRESS))]
+        addr: String,
+    },
 }
 
 fn main() -> Result<()> {
@@ -54,65 +96,78 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Command::Set { key, value, addr } => {
-            // println!("Key value pair to be set {:?} : {:?}", key, value);
-            let message = format!("SET\n{}\n{}\n", key, value);
-            // info!("Message request sent: {}", message);
-
-            let string_response = KvsClient::connect_and_send_request(addr, message)?;
-
-            let _trimmed_response = string_response.trim_start_matches('+');
-
-            // info!("Response: {}", trimmed_response);
-
-            // match result {
-            //     Ok(()) => process::exit(0),
-            //     Err(error) => {
-            //         println!("Error: {}", error);
-            //         process::exit(1);
-            //     }
-            // }
-
-            Ok(())
-        }
-        Command::Get { key, addr } => {
-            // info!("IP Address target: {:?}", addr);
-            let message = format!("GET\n{}\n", key);
-            // info!("Message request sent: {}", message);
-
-            let string_response = KvsClient::connect_and_send_request(addr, message)?;
-
-            let trimmed_response = string_response.trim_start_matches('+');
-
-            println!("{}", trimmed_response);
-
-            // info!("Response: {}", trimmed_response);
-
-            // match result {
-            //     Ok(Some(value)) => println!("{}", value),
-            //     Ok(None) => println!("Key not found"),
-            //     _ => unreachable!(),
-            // }
-
-            process::exit(0);
+        Command::Set { key, value, ttl, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            let result = match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, Duration::from_secs(ttl)),
+                None => client.set(key, value),
+            };
+            if let Err(error) = result {
+                fail(error);
+            }
         }
+        Command::Ttl { key, addr } => match KvsClient::connect(addr)?.ttl(key) {
+            //Rounded up, so a key that has not expired yet never shows 0
+            Ok(Some(ttl)) => println!("{}", ttl.as_millis().div_ceil(1000)),
+            Ok(None) => println!("No expiry"),
+            Err(KvsError::KeyNotFound) => println!("Key not found"),
+            Err(error) => fail(error),
+        },
+        Command::Get { key, addr } => match KvsClient::connect(addr)?.get(key) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => println!("Key not found"),
+            Err(error) => fail(error),
+        },
         Command::Rm { key, addr } => {
-            // info!("IP Address target: {:?}", addr);
-            let message = format!("RM\n{}\n", key);
-            // info!("Message request sent: {}", message);
-
-            let string_response = KvsClient::connect_and_send_request(addr, message)?;
-
-            let trimmed_response = string_response.trim_start_matches('+');
-
-            if trimmed_response.starts_with("Key not found") {
-                eprintln!("{}", trimmed_response);
-                process::exit(1);
+            if let Err(error) = KvsClient::connect(addr)?.remove(key) {
+                fail(error);
+            }
+        }
+        Command::Cas {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            let expected = expected.map(String::into_bytes);
+            let new = new.map(String::into_bytes);
+            match KvsClient::connect(addr)?.compare_and_swap(key.into_bytes(), expected, new) {
+                Ok(CasOutcome::Swapped) => {}
+                Ok(CasOutcome::Mismatch { current: Some(current) }) => {
+                    eprintln!("Value mismatch, current value: {}", String::from_utf8_lossy(&current));
+                    process::exit(1);
+                }
+                Ok(CasOutcome::Mismatch { current: None }) => {
+                    eprintln!("Value mismatch, key not found");
+                    process::exit(1);
+                }
+                Err(error) => fail(error),
+            }
+        }
+        Command::Scan {
+            prefix,
+            start_after,
+            limit,
+            addr,
+        } => {
+            let start_after = start_after.map(String::into_bytes);
+            match KvsClient::connect(addr)?.scan(prefix.into_bytes(), start_after, limit) {
+                Ok(entries) => {
+                    for (key, value) in entries {
+                        println!("{}\t{}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+                    }
+                }
+                Err(error) => fail(error),
             }
-
-            // info!("Response: {}", trimmed_response);
-
-            process::exit(0);
         }
     }
+
+    Ok(())
+}
+
+///Report an error and exit with a non-zero code
+fn fail(error: KvsError) -> ! {
+    eprintln!("{}", error);
+
+    process::exit(1);
 }
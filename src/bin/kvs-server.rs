@@ -1,7 +1,11 @@
 use clap::Parser;
+use kvs::engines::Durability;
 use kvs::error::Result;
-use kvs::server::KvsServer;
-use kvs::utils::KVS_CODE;
+use kvs::server::{KvsServer, ServerOptions};
+use kvs::AsyncKvsServer;
+use kvs::thread_pool::ThreadPoolKind;
+use kvs::utils::{DEFAULT_MAX_CONNECTIONS, KVS_CODE};
+use std::path::PathBuf;
 use tracing::{info, trace};
 
 const DEFAULT_IP_ADDRESS: &str = "127.0.0.1:4000";
@@ -15,6 +19,24 @@ struct Cli {
     ///Customize the engine used. Either kvs (built-in) or sled(plug-in)
     #[clap(short, long, default_value_t = String::from_utf8_lossy(KVS_CODE).to_string())]
     engine: String,
+    ///When writes are synced to disc: always, every:<ms>, group-commit or os-buffered
+    #[clap(short, long, default_value_t = Durability::default())]
+    durability: Durability,
+    ///Thread pool performing requests: naive, shared-queue or rayon
+    #[clap(short, long, default_value_t = ThreadPoolKind::default())]
+    pool: ThreadPoolKind,
+    ///Number of threads in the pool, at least 1. Defaults to the number of CPUs
+    #[clap(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    ///Most connections kept open at once, at least 1. Further connections are closed
+    #[clap(long, default_value_t = DEFAULT_MAX_CONNECTIONS as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: u64,
+    ///Directory the engine keeps its data in. Defaults to the working directory
+    #[clap(long, default_value = ".")]
+    data_dir: PathBuf,
+    ///Serve connections as tasks on a tokio runtime instead of on the thread pool
+    #[clap(long = "async")]
+    run_async: bool,
 }
 
 fn main() -> Result<()> {
@@ -33,6 +55,10 @@ fn main() -> Result<()> {
     );
     info!("Running kvs-server version: {}", env!("CARGO_PKG_VERSION"));
     info!("Engine used: {:?}", cli.engine);
+    info!("Durability: {}", cli.durability);
+    info!("Thread pool: {}", cli.pool);
+    info!("Async: {}", cli.run_async);
+    info!("Data directory: {}", cli.data_dir.display());
 
     eprintln!(
         "Beginning Server listening on IP Address:Port: {}",
@@ -43,8 +69,30 @@ fn main() -> Result<()> {
         env!("CARGO_PKG_VERSION")
     );
     eprintln!("Engine used: {:?}", cli.engine);
+    eprintln!("Durability: {}", cli.durability);
+    eprintln!("Thread pool: {}", cli.pool);
+    eprintln!("Async: {}", cli.run_async);
+    eprintln!("Data directory: {}", cli.data_dir.display());
 
-    KvsServer::route_request(cli.addr, cli.engine)?;
+    let defaults = ServerOptions::default();
+    let options = ServerOptions {
+        durability: cli.durability,
+        pool: cli.pool,
+        threads: cli.threads.unwrap_or(defaults.threads),
+        max_connections: usize::try_from(cli.max_connections).unwrap_or(usize::MAX),
+        data_dir: cli.data_dir,
+        ..defaults
+    };
+
+    if cli.run_async {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+
+        runtime.block_on(AsyncKvsServer::route_request(cli.addr, cli.engine, options))?;
+    } else {
+        KvsServer::route_request(cli.addr, cli.engine, options)?;
+    }
 
     Ok(())
 }
@@ -1,30 +1,289 @@
 // #![deny(missing_docs)]
 //!An implementation of a key value store in Rust
+use crate::engines::{CasOutcome, WriteBatch};
 use crate::error::{KvsError, Result};
-use crate::utils::BUFFER_LENGTH;
-use std::io::{Read, Write};
+use crate::protocol::{read_frame, read_frame_async, write_frame, write_frame_async, Request, Response};
 use std::net::TcpStream;
+use std::time::Duration;
 
-pub struct KvsClient {}
+///A connection to a KvsServer, reused for every request sent through it
+pub struct KvsClient {
+    stream: TcpStream,
+}
 
 impl KvsClient {
-    pub fn connect_and_send_request(ip_string: String, message: String) -> Result<String> {
-        let mut stream = TcpStream::connect(ip_string)?;
+    pub fn connect(ip_string: String) -> Result<KvsClient> {
+        let stream = TcpStream::connect(ip_string)?;
+        stream.set_nodelay(true)?;
+
+        Ok(KvsClient { stream })
+    }
+
+    ///Get the value of a key, or None if it has none
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.send_request(Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.send_request(Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Remove a key. Return KvsError::KeyNotFound if it does not exist
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.send_request(Request::Remove { key })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Set the value of a key for a limited time, after which it reads as missing
+    pub fn set_bytes_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let request = Request::SetWithTtl {
+            key,
+            value,
+            ttl_ms: u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX),
+        };
+
+        match self.send_request(request)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Time left before a key expires, or None if it never does. Return KvsError::KeyNotFound if it does not exist
+    pub fn ttl_bytes(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.send_request(Request::Ttl { key })? {
+            Response::Ttl(ttl_ms) => Ok(ttl_ms.map(Duration::from_millis)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    ///Apply several writes all together or not at all
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        match self.send_request(Request::Batch { batch })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Set a key to `new`, or remove it if `new` is None, only if its value is `expected`, where None means it has none
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        match self.send_request(Request::CompareAndSwap { key, expected, new })? {
+            Response::Ok => Ok(CasOutcome::Swapped),
+            Response::Mismatch { current } => Ok(CasOutcome::Mismatch { current }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Up to `limit` keys starting with a prefix, in order, with their values. Pass the last key returned as
+    ///`start_after` to get the next page
+    pub fn scan(
+        &mut self,
+        prefix: Vec<u8>,
+        start_after: Option<Vec<u8>>,
+        limit: Option<u64>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            prefix,
+            start_after,
+            limit,
+        };
+
+        match self.send_request(request)? {
+            Response::Entries(entries) => Ok(entries),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Get the value of a key as a string. Return an error if the value is not valid UTF-8
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        string_value(self.get_bytes(key.into_bytes())?)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    ///Send a request and wait for its response. A failed request is returned as the error the server reported,
+    ///KvsError::KeyNotFound for a missing key
+    pub fn send_request(&mut self, request: Request) -> Result<Response> {
+        write_frame(&mut self.stream, &request)?;
+
+        read_frame::<Response>(&mut self.stream)?.into_result()
+    }
+
+    ///Send a single request on a connection of its own
+    pub fn connect_and_send_request(ip_string: String, request: Request) -> Result<Response> {
+        KvsClient::connect(ip_string)?.send_request(request)
+    }
+}
+
+///Client speaking the same protocol as KvsClient without blocking the tokio runtime it runs on
+pub struct AsyncKvsClient {
+    stream: tokio::net::TcpStream,
+}
+
+impl AsyncKvsClient {
+    pub async fn connect(ip_string: String) -> Result<AsyncKvsClient> {
+        let stream = tokio::net::TcpStream::connect(ip_string).await?;
+        stream.set_nodelay(true)?;
+
+        Ok(AsyncKvsClient { stream })
+    }
+
+    ///Get the value of a key, or None if it has none
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.send_request(Request::Get { key }).await? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.send_request(Request::Set { key, value }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Remove a key. Return KvsError::KeyNotFound if it does not exist
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.send_request(Request::Remove { key }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Set the value of a key for a limited time, after which it reads as missing
+    pub async fn set_bytes_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let request = Request::SetWithTtl {
+            key,
+            value,
+            ttl_ms: u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX),
+        };
+
+        match self.send_request(request).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Time left before a key expires, or None if it never does. Return KvsError::KeyNotFound if it does not exist
+    pub async fn ttl_bytes(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.send_request(Request::Ttl { key }).await? {
+            Response::Ttl(ttl_ms) => Ok(ttl_ms.map(Duration::from_millis)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl).await
+    }
+
+    pub async fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes()).await
+    }
+
+    ///Apply several writes all together or not at all
+    pub async fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        match self.send_request(Request::Batch { batch }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Set a key to `new`, or remove it if `new` is None, only if its value is `expected`, where None means it has none
+    pub async fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        match self.send_request(Request::CompareAndSwap { key, expected, new }).await? {
+            Response::Ok => Ok(CasOutcome::Swapped),
+            Response::Mismatch { current } => Ok(CasOutcome::Mismatch { current }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    ///Up to `limit` keys starting with a prefix, in order, with their values. Pass the last key returned as
+    ///`start_after` to get the next page
+    pub async fn scan(
+        &mut self,
+        prefix: Vec<u8>,
+        start_after: Option<Vec<u8>>,
+        limit: Option<u64>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            prefix,
+            start_after,
+            limit,
+        };
+
+        match self.send_request(request).await? {
+            Response::Entries(entries) => Ok(entries),
+            response => Err(unexpected(response)),
+        }
+    }
 
-        stream.write(message.as_bytes())?;
+    ///Get the value of a key as a string. Return an error if the value is not valid UTF-8
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        string_value(self.get_bytes(key.into_bytes()).await?)
+    }
 
-        let mut buffer = [0; BUFFER_LENGTH];
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
 
-        stream.read(&mut buffer)?;
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
 
-        let byte_vector: Vec<&[u8]> = buffer.split(|byte| &[*byte] == b"\n").collect();
+    ///Send a request and wait for its response, returning a failed request as the error the server reported
+    pub async fn send_request(&mut self, request: Request) -> Result<Response> {
+        write_frame_async(&mut self.stream, &request).await?;
 
-        let content = byte_vector
-            .get(0)
-            .ok_or_else(|| KvsError::CommandError("Command unrecognized".to_string()))?;
+        read_frame_async::<Response>(&mut self.stream).await?.into_result()
+    }
 
-        let string_response = String::from_utf8(content[..].to_vec())?;
+    ///Send a single request on a connection of its own
+    pub async fn connect_and_send_request(ip_string: String, request: Request) -> Result<Response> {
+        AsyncKvsClient::connect(ip_string).await?.send_request(request).await
+    }
+}
+
+///Error for a response that does not answer the request it was sent for
+fn unexpected(response: Response) -> KvsError {
+    KvsError::CommandError(format!("Unexpected response: {:?}", response))
+}
 
-        Ok(string_response)
+///Turn a value read as bytes into a string
+fn string_value(value: Option<Vec<u8>>) -> Result<Option<String>> {
+    match value {
+        Some(value) => Ok(Some(String::from_utf8(value)?)),
+        None => Ok(None),
     }
 }
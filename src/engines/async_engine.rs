@@ -0,0 +1,94 @@
+use super::{CasOutcome, KvsEngine, WriteBatch};
+use crate::error::{KvsError, Result};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+///Async front for a KvsEngine, running its blocking calls on tokio's blocking thread pool so they never stall the runtime
+#[derive(Clone)]
+pub struct AsyncKvsEngine<E: KvsEngine> {
+    ///Handle new handles are cloned from. Behind a mutex as engines need not be Sync, while futures borrowing this are sent between threads
+    engine: Arc<Mutex<E>>,
+    ///Handles on the engine left over by finished calls, reused so each call does not have to clone a fresh one
+    idle: Arc<Mutex<Vec<E>>>,
+}
+
+impl<E: KvsEngine> AsyncKvsEngine<E> {
+    pub fn new(engine: E) -> AsyncKvsEngine<E> {
+        AsyncKvsEngine {
+            engine: Arc::new(Mutex::new(engine)),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |engine| engine.set(key, value)).await
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |engine| engine.remove(key)).await
+    }
+
+    pub async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.run(move |engine| engine.set_bytes(key, value)).await
+    }
+
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |engine| engine.get_bytes(key)).await
+    }
+
+    pub async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.run(move |engine| engine.remove_bytes(key)).await
+    }
+
+    pub async fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.run(move |engine| engine.set_with_ttl(key, value, ttl)).await
+    }
+
+    pub async fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.run(move |engine| engine.ttl(key)).await
+    }
+
+    pub async fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.run(move |engine| engine.apply_batch(batch)).await
+    }
+
+    pub async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        self.run(move |engine| engine.compare_and_swap(key, expected, new)).await
+    }
+
+    ///Run a blocking operation against a handle on the engine on the blocking thread pool
+    pub async fn run<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let engine = self.checkout();
+        let idle = Arc::clone(&self.idle);
+
+        tokio::task::spawn_blocking(move || {
+            let result = operation(&engine);
+            idle.lock().expect("idle engines poisoned").push(engine);
+            result
+        })
+        .await
+        .map_err(|err| KvsError::Store(format!("Engine task failed: {}", err)))?
+    }
+
+    ///  Take an idle handle on the engine, or clone a new one when every handle is busy
+    fn checkout(&self) -> E {
+        self.idle
+            .lock()
+            .expect("idle engines poisoned")
+            .pop()
+            .unwrap_or_else(|| self.engine.lock().expect("engine poisoned").clone())
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+///A group of writes to several keys that an engine applies all together or not at all, in the order they were added.
+///Removing a key that does not exist is not an error in a batch, so a batch never fails part way through
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+///A single write in a WriteBatch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    ///Add setting the value of a key to the batch
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    ///Add removing a key to the batch
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
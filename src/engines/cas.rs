@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+///Outcome of a compare-and-swap that was carried out, whether or not the key held the expected value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CasOutcome {
+    ///The key held the expected value and now holds the new one
+    Swapped,
+    ///The key held another value, or none, and was left as it was
+    Mismatch { current: Option<Vec<u8>> },
+}
+
+impl CasOutcome {
+    pub fn is_swapped(&self) -> bool {
+        matches!(self, CasOutcome::Swapped)
+    }
+}
@@ -0,0 +1,256 @@
+use crate::error::{KvsError, Result};
+use std::fmt;
+use std::fs::File;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::warn;
+
+///When acknowledged writes are forced to disc, trading write latency for safety on a crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    ///Every write is synced to disc before it is acknowledged
+    Always,
+    ///Writes are synced to disc in the background every given number of milliseconds
+    EveryMs(u64),
+    ///Every write is synced to disc before it is acknowledged, but writers waiting at the same time share a single sync
+    GroupCommit,
+    ///Writes are handed to the operating system, which decides when they reach the disc
+    #[default]
+    OsBuffered,
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    ///Parse `always`, `every:<ms>`, `group-commit` or `os-buffered`
+    fn from_str(s: &str) -> Result<Durability> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "group-commit" => Ok(Durability::GroupCommit),
+            "os-buffered" => Ok(Durability::OsBuffered),
+            _ => s
+                .strip_prefix("every:")
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .filter(|ms| *ms > 0)
+                .map(Durability::EveryMs)
+                .ok_or_else(|| {
+                    KvsError::CommandError(format!(
+                        "Unknown durability {}. Expected always, every:<ms>, group-commit or os-buffered",
+                        s
+                    ))
+                }),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::EveryMs(ms) => write!(f, "every:{}", ms),
+            Durability::GroupCommit => write!(f, "group-commit"),
+            Durability::OsBuffered => write!(f, "os-buffered"),
+        }
+    }
+}
+
+///Syncs the segments a KvStore writes to according to its durability
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    durability: Durability,
+    shared: Arc<SyncShared>,
+    periodic: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+#[derive(Debug, Default)]
+struct SyncShared {
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    ///Number of writes made so far
+    written: u64,
+    ///Number of writes known to be on disc
+    synced: u64,
+    ///Whether a writer is currently syncing on behalf of the others
+    syncing: bool,
+    ///Whether the active segment holds writes that have not been synced yet
+    dirty: bool,
+    ///Handle on the active segment that the periodic sync and group commits flush
+    active: Option<Arc<File>>,
+    ///Number of times a segment has been synced to disc
+    syncs: u64,
+}
+
+///A write that has to reach the disc before it is acknowledged under group commit. It is waited on once the
+///writer lock is released, so writes arriving in the meantime can share the same sync
+#[must_use]
+#[derive(Debug)]
+pub(crate) struct PendingSync {
+    shared: Arc<SyncShared>,
+    write: u64,
+}
+
+impl Syncer {
+    pub(crate) fn new(durability: Durability) -> Syncer {
+        let shared = Arc::new(SyncShared::default());
+
+        let periodic = match durability {
+            Durability::EveryMs(ms) => {
+                let (stop, stopped) = mpsc::channel::<()>();
+                let shared = Arc::clone(&shared);
+
+                let handle = thread::spawn(move || loop {
+                    let stopping = !matches!(
+                        stopped.recv_timeout(Duration::from_millis(ms)),
+                        Err(RecvTimeoutError::Timeout)
+                    );
+
+                    let mut state = shared.state.lock().expect("sync state poisoned");
+                    let dirty = match state.dirty {
+                        true => state.active.clone(),
+                        false => None,
+                    };
+                    state.dirty = false;
+                    drop(state);
+
+                    if let Some(file) = dirty {
+                        match file.sync_data() {
+                            Ok(()) => shared.state.lock().expect("sync state poisoned").syncs += 1,
+                            Err(err) => warn!("Unable to sync the active segment: {}", err),
+                        }
+                    }
+
+                    if stopping {
+                        break;
+                    }
+                });
+
+                Some((stop, handle))
+            }
+            _ => None,
+        };
+
+        Syncer {
+            durability,
+            shared,
+            periodic,
+        }
+    }
+
+    ///Start tracking a newly opened active segment, which the periodic sync and group commits flush from now on
+    pub(crate) fn activated(&self, file: &File) -> Result<()> {
+        if let Durability::EveryMs(_) | Durability::GroupCommit = self.durability {
+            let file = Arc::new(file.try_clone()?);
+            self.shared.state.lock().expect("sync state poisoned").active = Some(file);
+        }
+
+        Ok(())
+    }
+
+    ///Make a write to the active segment as durable as the policy asks for before it is acknowledged. Under group commit
+    ///the write is only counted here, and the sync it needs is returned to be waited on after the writer lock is released
+    pub(crate) fn wrote(&self, file: &File) -> Result<Option<PendingSync>> {
+        match self.durability {
+            Durability::Always => {
+                file.sync_data()?;
+                self.shared.state.lock().expect("sync state poisoned").syncs += 1;
+                Ok(None)
+            }
+            Durability::GroupCommit => {
+                let mut state = self.shared.state.lock().expect("sync state poisoned");
+                state.written += 1;
+
+                Ok(Some(PendingSync {
+                    shared: Arc::clone(&self.shared),
+                    write: state.written,
+                }))
+            }
+            Durability::EveryMs(_) => {
+                self.shared.state.lock().expect("sync state poisoned").dirty = true;
+                Ok(None)
+            }
+            Durability::OsBuffered => Ok(None),
+        }
+    }
+
+    ///Number of times a segment has been synced to disc
+    pub(crate) fn syncs(&self) -> u64 {
+        self.shared.state.lock().expect("sync state poisoned").syncs
+    }
+
+    ///Sync the active segment once it will not be written to again, unless syncing is left to the operating system
+    pub(crate) fn sealed(&self, file: &File) -> Result<()> {
+        match self.durability {
+            Durability::OsBuffered => Ok(()),
+            _ => {
+                let mut state = self.shared.state.lock().expect("sync state poisoned");
+                state.dirty = false;
+                let written = state.written;
+                drop(state);
+
+                //If this fails the segment stays active, so writes waiting on a group commit sync it themselves
+                file.sync_data()?;
+
+                //Every write counted so far went to this segment or to one sealed before it
+                let mut state = self.shared.state.lock().expect("sync state poisoned");
+                state.active = None;
+                state.synced = state.synced.max(written);
+                state.syncs += 1;
+                self.shared.synced.notify_all();
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl PendingSync {
+    ///Wait until the write is on disc. The first writer to find no sync in progress syncs the active segment
+    ///for everyone who wrote before it, and writers arriving meanwhile wait for the next sync
+    pub(crate) fn wait(self) -> Result<()> {
+        let mut state = self.shared.state.lock().expect("sync state poisoned");
+
+        loop {
+            if state.synced >= self.write {
+                return Ok(());
+            }
+
+            //Sealing a segment syncs every write made so far, so there is always an active segment while one is outstanding
+            if let (false, Some(file)) = (state.syncing, state.active.clone()) {
+                state.syncing = true;
+                let target = state.written;
+                drop(state);
+
+                let result = file.sync_data();
+
+                state = self.shared.state.lock().expect("sync state poisoned");
+                state.syncing = false;
+                if result.is_ok() {
+                    state.synced = state.synced.max(target);
+                    state.syncs += 1;
+                }
+                self.shared.synced.notify_all();
+
+                return Ok(result?);
+            }
+
+            state = self.shared.synced.wait(state).expect("sync state poisoned");
+        }
+    }
+}
+
+impl Drop for Syncer {
+    ///Stop the periodic sync, syncing whatever is still outstanding
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.periodic.take() {
+            let _ = stop.send(());
+            let _ = handle.join();
+        }
+    }
+}
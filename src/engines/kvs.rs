@@ -1,257 +1,1711 @@
 ///Primary struct is a KvStore containing a single HashMap
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::path::PathBuf;
-use crate::utils::{ KVS_FILE_NAME };
-use std::io::BufWriter;
+use std::ffi::OsStr;
+use std::ops::{Bound, RangeBounds};
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+use crate::utils::{
+    BATCH_FORMAT_VERSION, BATCH_RECORD, BINARY_FORMAT_VERSION, COMPACTION_FILE_EXTENSION, EXPIRY_FORMAT_VERSION,
+    EXPIRY_LENGTH, DEFAULT_COMPACTION_THRESHOLD, DEFAULT_SEGMENT_SIZE, FRAME_HEADER_LENGTH, HINT_ENTRY_HEADER_LENGTH,
+    HINT_FILE_EXTENSION, HINT_FORMAT_VERSION, HINT_MAGIC, KVS_FILE_NAME, LOG_FILE_EXTENSION, LOG_FORMAT_VERSION, RECORD_HEADER_LENGTH,
+    RM_RECORD, SEGMENT_MAGIC, SET_RECORD, SET_WITH_EXPIRY_RECORD,
+};
+use std::io::{ BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write };
 use std::fs::{ self, File };
 use crate::error::{ KvsError, Result };
-use serde::{ Deserialize, Serialize };
-use super::KvsEngine;
+use crossbeam_skiplist::{ SkipMap, SkipSet };
+use crossbeam_utils::atomic::AtomicCell;
+use serde::Deserialize;
+use tracing::warn;
+use super::durability::{ Durability, PendingSync, Syncer };
+use super::lock::DirectoryLock;
+use super::metadata::Metadata;
+use super::{before_end, expiry_after, is_expired, now_millis, time_left, BatchOp, CasOutcome, KvsEngine, WriteBatch};
 
+///A handle on a log-structured store. Clones share the index and the single writer, so any number of threads can
+///read concurrently while writes are serialized. Reads never take a lock: give each thread its own clone
 #[derive(Debug)]
 pub struct KvStore {
-    pub kv: HashMap<String, usize>,
     pub directory_path: PathBuf,
-    pub log_pointer: usize,
+    index: Arc<Index>,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    ///Open segment readers, kept per handle so clones on different threads never share them
+    readers: RefCell<HashMap<u64, BufReader<File>>>,
 }
 
-impl KvStore {
-    ///Create a hashmap
-    pub fn new(path: PathBuf) -> KvStore {
-        KvStore {
-            kv: HashMap::new(),
-            directory_path: path,
-            log_pointer: 0,
+///Where the live value of every key is on disc, and how each segment is laid out. Updated by the writer and by
+///the compaction it runs in the background, and read without locking
+#[derive(Debug, Default)]
+struct Index {
+    kv: SkipMap<Vec<u8>, AtomicCell<CommandPos>>,
+    ///Every key that expires along with when it does, ordered by expiry time so expired keys are found without
+    ///going through every key
+    expiry: SkipSet<(u64, Vec<u8>)>,
+    ///Held while keys are pointed somewhere new or removed, so the writer and an installing compaction take turns
+    updating: Mutex<()>,
+    segment_formats: SkipMap<u64, SegmentFormat>,
+    ///Segments with a lower generation have been compacted away, so readers close their handles on them
+    safe_point: AtomicU64,
+    ///Bytes on disc taken up by commands that have been overwritten or removed
+    stale_bytes: AtomicU64,
+}
+
+///The part of a KvStore that appends to the log and compacts it, shared by every clone behind a single lock
+#[derive(Debug)]
+struct KvStoreWriter {
+    directory_path: PathBuf,
+    index: Arc<Index>,
+    current_gen: u64,
+    options: KvStoreOptions,
+    compaction: Option<CompactionJob>,
+    syncer: Syncer,
+    ///The group commit sync the last write is waiting on, taken once the writer lock is released
+    pending_sync: Option<PendingSync>,
+    writer: Option<SegmentWriter>,
+    ///Segment and offset a write failed part way through at, which the segment is cut back to before anything else
+    ///is appended
+    partial_write: Option<(u64, u64)>,
+    ///Held for as long as the store is open, so no other handle writes to the same directory
+    _lock: DirectoryLock,
+}
+
+///Tunable settings for a KvStore
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    ///Size in bytes at which the active segment is closed and a new one started
+    pub segment_size: u64,
+    ///Number of stale bytes at which a background compaction is started
+    pub compaction_threshold: u64,
+    ///When writes are synced to disc
+    pub durability: Durability,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            durability: Durability::default(),
+        }
+    }
+}
+
+///Position of a serialized command in the log: the segment generation, byte offset and length, along with when the
+///value it sets expires so that expired keys are recognised without reading them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandPos {
+    pub gen: u64,
+    pub offset: u64,
+    pub len: u64,
+    ///Milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(is_expired)
+    }
+}
+
+///How the commands in a segment are laid out on disc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SegmentFormat {
+    ///Bare JSON commands one after another, as written before segments had a header
+    Legacy,
+    ///Format version 1: length-prefixed, checksummed JSON frames
+    JsonFrames,
+    ///Checksummed binary records in the given format version. Version 2 holds sets and removals, version 3 adds batches
+    ///and version 4 values that expire
+    Binary(u8),
+}
+
+impl SegmentFormat {
+    ///The format written by this version
+    const CURRENT: SegmentFormat = SegmentFormat::Binary(LOG_FORMAT_VERSION);
+
+    ///The format a segment header's version refers to
+    fn from_version(version: u8) -> Option<SegmentFormat> {
+        match version {
+            1 => Some(SegmentFormat::JsonFrames),
+            BINARY_FORMAT_VERSION..=LOG_FORMAT_VERSION => Some(SegmentFormat::Binary(version)),
+            _ => None,
         }
     }
+}
+
+impl Index {
+    ///Where the live value of a key is on disc, if it has one that has not expired
+    fn position(&self, key: &[u8]) -> Option<CommandPos> {
+        self.kv
+            .get(key)
+            .map(|entry| entry.value().load())
+            .filter(|command_pos| !command_pos.is_expired())
+    }
 
+    ///The first key in the index within a lower bound
+    fn first_key(&self, start: Bound<&Vec<u8>>) -> Option<Vec<u8>> {
+        self.kv.lower_bound(start).map(|entry| entry.key().clone())
+    }
+
+    ///Point a key at a new position, returning the old one. Only the writer calls this, and a key that is already
+    ///in the index is updated in place, since replacing its entry would briefly hide it from readers
+    fn set_position(&self, key: Vec<u8>, command_pos: CommandPos) -> Option<CommandPos> {
+        let _updating = self.updating.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(expires_at) = command_pos.expires_at {
+            self.expiry.insert((expires_at, key.clone()));
+        }
+
+        let old_pos = match self.kv.get(&key) {
+            Some(entry) => Some(entry.value().swap(command_pos)),
+            None => {
+                self.kv.insert(key.clone(), AtomicCell::new(command_pos));
+                None
+            }
+        };
+
+        if let Some(expires_at) = old_pos.and_then(|old_pos| old_pos.expires_at) {
+            if command_pos.expires_at != Some(expires_at) {
+                self.expiry.remove(&(expires_at, key));
+            }
+        }
+
+        old_pos
+    }
+
+    ///Remove a key, returning the position it had
+    fn remove_position(&self, key: &[u8]) -> Option<CommandPos> {
+        let _updating = self.updating.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let old_pos = self.kv.remove(key).map(|entry| entry.value().load());
+        self.forget_expiry(key, old_pos);
+
+        old_pos
+    }
+
+    ///Remove a key only if it still has the given position, which a write may have replaced since it was looked up.
+    ///Return whether it was removed
+    fn remove_position_if(&self, key: &[u8], command_pos: CommandPos) -> bool {
+        let _updating = self.updating.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match self.kv.get(key) {
+            Some(entry) if entry.value().load() == command_pos => {
+                entry.remove();
+                self.forget_expiry(key, Some(command_pos));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    ///Remove a key that expires at the given time, returning its position. A key that has been written since no
+    ///longer expires then, so only its stale entry in the expiry index is dropped
+    fn remove_expiring(&self, expires_at: u64, key: &[u8]) -> Option<CommandPos> {
+        let _updating = self.updating.lock().unwrap_or_else(PoisonError::into_inner);
+
+        self.expiry.remove(&(expires_at, key.to_vec()));
+
+        match self.kv.get(key) {
+            Some(entry) if entry.value().load().expires_at == Some(expires_at) => {
+                let command_pos = entry.value().load();
+                entry.remove();
+                Some(command_pos)
+            }
+            _ => None,
+        }
+    }
+
+    ///  Drop a removed position's entry in the expiry index, if it expires
+    fn forget_expiry(&self, key: &[u8], old_pos: Option<CommandPos>) {
+        if let Some(expires_at) = old_pos.and_then(|old_pos| old_pos.expires_at) {
+            self.expiry.remove(&(expires_at, key.to_vec()));
+        }
+    }
+}
+
+///Outcome of reading a single record from a segment
+enum RecordRead {
+    ///A valid command and the length of its record
+    Command(Command, u64),
+    ///The commands of a valid batch, each with the offset and length of its record within the batch's,
+    ///and the length of the batch's record
+    Batch(Vec<(Command, u64, u64)>, u64),
+    ///The segment ends before the record does
+    Torn,
+    ///A complete record of the given length that fails its checksum or cannot be decoded
+    Invalid(u64),
+}
+
+///Open handle on the active segment that commands are appended to
+#[derive(Debug)]
+struct SegmentWriter {
+    gen: u64,
+    ///Written to directly, since every record is appended with a single write and durability decides when it is synced
+    file: File,
+    ///Length of the segment, where the next command is written
+    offset: u64,
+}
+
+///A change to the index read back from a segment or its hint file
+enum IndexUpdate {
+    Set(Vec<u8>, CommandPos),
+    Rm(Vec<u8>, CommandPos),
+    ///The updates of a batch, which were read back whole
+    Batch(Vec<IndexUpdate>),
+}
+
+impl IndexUpdate {
+    ///The update a command read back from a segment makes
+    fn from_command(command: Command, command_pos: CommandPos) -> IndexUpdate {
+        match command {
+            Command::Set { key, expires_at, .. } => IndexUpdate::Set(key, CommandPos { expires_at, ..command_pos }),
+            Command::Rm { key } => IndexUpdate::Rm(key, command_pos),
+        }
+    }
+}
+
+///Every key a hint file lists, with the position of its record in the segment
+type HintEntries = Vec<(Vec<u8>, CommandPos)>;
+
+///A compaction running on a background thread, which installs it as soon as it is written
+#[derive(Debug)]
+struct CompactionJob {
+    handle: JoinHandle<Result<()>>,
+}
+
+///The compacted segment written by a background compaction, waiting to be swapped in
+#[derive(Debug)]
+struct CompactionOutput {
+    compacted_gen: u64,
+    sealed_gens: Vec<u64>,
+    ///Every live key that was copied, with its position before and after compaction
+    moved: Vec<(Vec<u8>, CommandPos, CommandPos)>,
+    ///Every key that had expired and was left behind, with its position in the sealed segments
+    expired: Vec<(Vec<u8>, CommandPos)>,
+}
+
+impl KvStore {
     ///Open the KvStore at a given path. Return the KvStore
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        // println!("opening file");
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    ///Open the KvStore at a given path with custom options, recording it in the directory's META file.
+    ///Return the KvStore, or an error if another handle has it open or the directory holds another engine's data
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let mut directory: PathBuf = path.into();
+        //An empty path refers to the working directory
+        if directory.as_os_str().is_empty() {
+            directory = PathBuf::from(".");
+        }
+        let lock = DirectoryLock::acquire(&directory)?;
+        let metadata = Metadata::verify(&directory, "kvs")?;
 
-        let directory: PathBuf = path.into();
-        fs::create_dir_all(&directory)?;
+        migrate_legacy_log(&directory)?;
+        remove_unfinished_compactions(&directory)?;
+        remove_orphaned_hints(&directory)?;
 
-        let full_path = directory.join(KVS_FILE_NAME);
-        // println!("full path: {:?}", full_path);
+        let gen_list = sorted_gen_list(&directory)?;
 
-        let file = get_file(full_path.clone())?;
-        // println!("file opened");
+        let index = Index::default();
+
+        //read every segment, oldest first, into a series of index updates along with their position on disc.
+        //A compacted segment's hint file holds its keys and positions, so its values do not have to be read
+        let mut index_updates: Vec<IndexUpdate> = Vec::new();
+        let mut last_gen_hinted = false;
+        for &gen in gen_list.iter() {
+            last_gen_hinted = match load_hint(&directory, gen)? {
+                Some(entries) => {
+                    index.segment_formats.insert(gen, SegmentFormat::CURRENT);
+                    index_updates.extend(
+                        entries.into_iter().map(|(key, command_pos)| IndexUpdate::Set(key, command_pos)),
+                    );
+                    true
+                }
+                None => {
+                    let newest = gen_list.last() == Some(&gen);
+                    let (format, updates) = deserialize_index_updates_from_file(&directory, gen, newest)?;
+                    index.segment_formats.insert(gen, format);
+                    index_updates.extend(updates);
+                    false
+                }
+            };
+        }
 
-        //read the log file into a series of commands
-        let deserialized_commands: Vec<Command> = deserialize_commands_from_file(file);
+        //"replay" the updates into the index in memory, leaving out values that have expired since
+        let mut kv = HashMap::new();
+        let stale_bytes = build_log_pointers(&mut kv, index_updates, now_millis());
+        index.stale_bytes.store(stale_bytes, Ordering::SeqCst);
+        for (key, command_pos) in kv.into_iter() {
+            index.set_position(key, command_pos);
+        }
 
-        // println!("Deserialized Commands: {:?} ", deserialized_commands);
+        //Writes continue in the newest segment; every older segment is sealed and can be compacted.
+        //A segment in an older format, or a compacted one described by a hint, is never appended to, so writes start a new one instead
+        let current_gen = match gen_list.last() {
+            Some(&gen)
+                if !last_gen_hinted
+                    && index.segment_formats.get(&gen).map(|entry| *entry.value()) == Some(SegmentFormat::CURRENT) =>
+            {
+                gen
+            }
+            Some(&gen) => gen + 1,
+            None => 1,
+        };
 
-        //"replay" commands into the HashMap in memory -> for each command, match against commands and execute
-        let mut in_mem_kv = KvStore::new(directory);
-        build_log_pointers(&mut in_mem_kv, deserialized_commands.clone());
+        //Segments in older formats are migrated by compacting them in the background
+        let needs_migration = index.segment_formats.iter().any(|entry| *entry.value() != SegmentFormat::CURRENT);
 
-        // println!("In memory pointer map: {:?}", in_mem_kv.kv);
+        let index = Arc::new(index);
+        let mut writer = KvStoreWriter {
+            directory_path: directory.clone(),
+            index: Arc::clone(&index),
+            current_gen,
+            syncer: Syncer::new(options.durability),
+            partial_write: None,
+            pending_sync: None,
+            options,
+            compaction: None,
+            writer: None,
+            _lock: lock,
+        };
 
-        //Compaction
-        //println!("Old disc before compaction: {:?} ", deserialized_commands);
-        let mut new_disc: Vec<Command> = Vec::new();
-        perform_compaction(&mut in_mem_kv, deserialized_commands, &mut new_disc);
+        metadata.store(&directory)?;
 
-        //write new Vec<Command> to disc & check that pointer values in memory reflect correct disc pointer
-        //println!("New compacted disc: {:?} ", new_disc);
-        //println!("New log pointer map: {:?} ", in_mem_kv.kv);
-        //println!("New Store pointer value: {:?}", in_mem_kv.log_pointer);
+        if needs_migration {
+            writer.start_compaction()?;
+        }
 
-        // println!("Attempting to write");
+        Ok(KvStore {
+            directory_path: directory,
+            index,
+            writer: Arc::new(Mutex::new(writer)),
+            readers: RefCell::new(HashMap::new()),
+        })
+    }
 
-        //TODO: Is there a more efficient way to write multiple Commands to disc? Seems like opening a new file handle for each write is inefficient. Perhaps write Vec<Command> to file and figure out how to deserialize that?
-        let _clean_file = fs::OpenOptions::new()
-            .truncate(true)
-            .write(true)
-            .open(full_path.clone())?;
+    ///Whether a directory holds KvStore log segments
+    pub fn log_exists(directory: &Path) -> bool {
+        directory.join(KVS_FILE_NAME).exists()
+            || sorted_gen_list(directory).is_ok_and(|gen_list| !gen_list.is_empty())
+    }
 
-        for command in new_disc.iter() {
-            let file = fs::OpenOptions::new()
-                .append(true)
-                .open(full_path.clone())?;
+    ///Compact the log now, waiting for any compaction already running in the background to finish first
+    pub fn compact(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
 
-            // println!("File opened successfully");
+        if let Some(job) = writer.compaction.take() {
+            job.wait()?;
+        }
 
-            let f = BufWriter::new(file);
+        writer.start_compaction()?;
 
-            serde_json::to_writer(f, &command)?;
+        if let Some(job) = writer.compaction.take() {
+            job.wait()?;
         }
 
-        // println!("Write complete");
-        Ok(in_mem_kv)
+        Ok(())
+    }
+
+    ///Bytes on disc taken up by commands that have been overwritten or removed and not yet compacted away
+    pub fn stale_bytes(&self) -> Result<u64> {
+        Ok(self.index.stale_bytes.load(Ordering::SeqCst))
     }
 
-    ///  Get the file path for the disc log
-    fn get_file_path(&self) -> PathBuf {
-        self.directory_path.join("log.txt")
+    ///Number of times the log has been synced to disc since the store was opened
+    pub fn syncs(&self) -> Result<u64> {
+        Ok(self.lock_writer()?.syncer.syncs())
+    }
+
+    ///  Read the value of the Set command at the given position through this handle's own reader for its segment
+    fn read_value(&self, command_pos: &CommandPos) -> Result<Vec<u8>> {
+        let format = self
+            .index
+            .segment_formats
+            .get(&command_pos.gen)
+            .map(|entry| *entry.value())
+            .unwrap_or(SegmentFormat::CURRENT);
+
+        //Readers stay open across calls until compaction removes their segment
+        let mut readers = self.readers.borrow_mut();
+        let safe_point = self.index.safe_point.load(Ordering::SeqCst);
+        readers.retain(|gen, _| *gen >= safe_point);
+
+        let reader = match readers.entry(command_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = File::open(log_path(&self.directory_path, command_pos.gen))?;
+                entry.insert(BufReader::new(file))
+            }
+        };
+        reader.seek(SeekFrom::Start(command_pos.offset))?;
+
+        //Only the bytes of the command the pointer refers to are read and deserialized
+        let command_on_disc = read_command(reader, format, command_pos)?;
+
+        if let Command::Set { value, .. } = command_on_disc {
+            Ok(value)
+        } else {
+            Err(KvsError::Store(
+                "Unable to find key through the log pointer".to_owned(),
+            ))
+        }
+    }
+
+    ///  Take the writer lock shared by every clone of this store
+    fn lock_writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        self.writer
+            .lock()
+            .map_err(|_| KvsError::Store("Writer lock poisoned".to_owned()))
+    }
+
+    ///  Make a write under the writer lock, then wait for it to reach the disc if it is group committed.
+    ///  The wait happens after the lock is released so writes made meanwhile can share the sync
+    fn write<T>(&self, op: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        let mut writer = self.lock_writer()?;
+        let result = op(&mut writer);
+        let pending_sync = writer.pending_sync.take();
+        drop(writer);
+
+        if let Some(pending_sync) = pending_sync {
+            pending_sync.wait()?;
+        }
+
+        result
     }
 }
 
-impl KvsEngine for KvStore {
+impl Clone for KvStore {
+    ///Share the index and writer with a new handle, which opens its own segment readers
+    fn clone(&self) -> KvStore {
+        KvStore {
+            directory_path: self.directory_path.clone(),
+            index: Arc::clone(&self.index),
+            writer: Arc::clone(&self.writer),
+            readers: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl KvStoreWriter {
+    ///  Set the value of a key in the log and the index, expiring at the given time if there is one
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let command = Command::Set { key, value, expires_at };
+
+        let command_pos = self.append_command(&command)?;
+
+        if let Command::Set { key, .. } = command {
+            if let Some(old_pos) = self.index.set_position(key, command_pos) {
+                self.index.stale_bytes.fetch_add(old_pos.len, Ordering::SeqCst);
+            }
+        }
+
+        self.maybe_compact()
+    }
+
+    ///  Remove a key from the log and the index. Return an error if it does not exist or has expired
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.index.position(&key).is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+
+        let command = Command::Rm { key };
+
+        let command_pos = self.append_command(&command)?;
+
+        //The value may have expired and been swept since it was looked up, in which case its bytes are already counted
+        let old_pos = match command {
+            Command::Rm { key } => self.index.remove_position(&key),
+            Command::Set { .. } => None,
+        };
+
+        //Both the removed value and the removal itself are reclaimed by compaction
+        self.index
+            .stale_bytes
+            .fetch_add(old_pos.map_or(0, |old_pos| old_pos.len) + command_pos.len, Ordering::SeqCst);
 
-    ///Set the value of a string key to a string. Return an error if the value is not written successfully.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.kv.insert(key.clone(), self.log_pointer);
+        self.maybe_compact()
+    }
+
+    ///  Append a command to the end of the active segment. Return the position it was written at
+    fn append_command(&mut self, command: &Command) -> Result<CommandPos> {
+        let command_pos = self.append_record(&encode_record(command)?)?;
+
+        Ok(CommandPos {
+            expires_at: command.expires_at(),
+            ..command_pos
+        })
+    }
+
+    ///  Append a batch of commands to the end of the active segment as a single record. Return the position of each
+    ///  command's record within it
+    fn append_batch(&mut self, commands: &[Command]) -> Result<Vec<CommandPos>> {
+        let (record, positions) = encode_batch(commands)?;
+        let batch_pos = self.append_record(&record)?;
+
+        Ok(positions
+            .into_iter()
+            .zip(commands)
+            .map(|((offset, len), command)| CommandPos {
+                gen: batch_pos.gen,
+                offset: batch_pos.offset + offset,
+                len,
+                expires_at: command.expires_at(),
+            })
+            .collect())
+    }
 
-        let command = Command::Set { key, value };
+    ///  Append an encoded record to the end of the active segment. Return the position it was written at
+    fn append_record(&mut self, record: &[u8]) -> Result<CommandPos> {
+        self.open_active_segment()?;
+        let segment = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| KvsError::Store("Active segment is not open".to_owned()))?;
+        let offset = segment.offset;
 
-        let full_path = self.get_file_path();
-        // println!("set full path: {:?}", full_path);
+        //The record is handed to the operating system straight away so reads through other handles see it.
+        //A write that fails may have left part of it behind, so the writer is dropped and the segment cut back
+        //to where the record started before anything else is appended
+        if let Err(err) = segment.file.write_all(record) {
+            self.writer = None;
+            self.partial_write = Some((self.current_gen, offset));
+            return Err(err.into());
+        }
+        segment.offset += record.len() as u64;
 
-        // let file = File::open(full_path)?;
+        if let Some(pending_sync) = self.syncer.wrote(&segment.file)? {
+            self.pending_sync = Some(pending_sync);
+        }
 
-        let file = get_file(full_path)?;
+        let command_pos = CommandPos {
+            gen: self.current_gen,
+            offset,
+            len: record.len() as u64,
+            expires_at: None,
+        };
 
-        serde_json::to_writer(file, &command)?;
+        //Roll over to a new segment once the active one reaches the configured size
+        if offset + command_pos.len >= self.options.segment_size {
+            self.seal_active_segment()?;
+        }
 
-        // println!("Set write complete");
+        Ok(command_pos)
+    }
 
-        self.log_pointer += 1;
+    ///  Open a writer for the active segment unless one is open already, first cutting away what a failed write left
+    ///  behind. A new segment starts with its header
+    fn open_active_segment(&mut self) -> Result<()> {
+        if let Some((gen, offset)) = self.partial_write {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .open(log_path(&self.directory_path, gen))?;
+            file.set_len(offset)?;
+            self.partial_write = None;
+        }
+
+        if self.writer.as_ref().map(|segment| segment.gen) != Some(self.current_gen) {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(log_path(&self.directory_path, self.current_gen))?;
+            let mut offset = file.seek(SeekFrom::End(0))?;
+
+            if offset == 0 {
+                if let Err(err) = file.write_all(&segment_header()) {
+                    self.partial_write = Some((self.current_gen, 0));
+                    return Err(err.into());
+                }
+                offset = segment_header().len() as u64;
+                self.index.segment_formats.insert(self.current_gen, SegmentFormat::CURRENT);
+            }
+
+            self.syncer.activated(&file)?;
+
+            self.writer = Some(SegmentWriter {
+                gen: self.current_gen,
+                file,
+                offset,
+            });
+        }
 
         Ok(())
     }
 
-    ///Remove a given key. Return an error if the key does not exist or is not removed successfully.
-    fn remove(&mut self, key: String) -> Result<()> {
-        let result = self.kv.remove(&key);
+    ///  Move writes on to a new segment, making sure everything written to the active one is synced first
+    fn seal_active_segment(&mut self) -> Result<()> {
+        if let Some(segment) = self.writer.take() {
+            self.syncer.sealed(&segment.file)?;
+        }
+
+        self.current_gen += 1;
+
+        Ok(())
+    }
 
-        // println!("Remove result: {:?}", result.clone());
+    ///  Clear away a finished background compaction, and start a new one once enough stale data has built up
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.compaction.as_ref().is_some_and(|job| job.handle.is_finished()) {
+            if let Some(job) = self.compaction.take() {
+                //A failed compaction leaves every segment the index points to in place, so writes carry on and it is retried later
+                if let Err(err) = job.wait() {
+                    warn!("Unable to finish compaction: {}", err);
+                }
+            }
+        }
 
-        if result.is_none() {
-            return Err(KvsError::Store("Key not found".to_owned()));
+        if self.compaction.is_none()
+            && self.index.stale_bytes.load(Ordering::SeqCst) > self.options.compaction_threshold
+        {
+            self.start_compaction()?;
         }
 
-        // println!("Writing remove to disk");
+        Ok(())
+    }
 
-        let command = Command::Rm { key };
+    ///  Seal the active segment and compact every sealed segment on a background thread, which swaps the result in
+    ///  without waiting for the writer. The compacted segment gets the generation right after the sealed segments
+    ///  and writes move on to the one after it.
+    fn start_compaction(&mut self) -> Result<()> {
+        self.seal_active_segment()?;
+        let compacted_gen = self.current_gen;
+        self.current_gen += 1;
 
-        let full_path = self.get_file_path();
-        // println!("set remove full path: {:?}", full_path);
+        let sealed_gens: Vec<u64> = sorted_gen_list(&self.directory_path)?
+            .into_iter()
+            .filter(|gen| *gen < compacted_gen)
+            .collect();
 
-        // let file = File::open(full_path)?;
+        if sealed_gens.is_empty() {
+            return Ok(());
+        }
 
-        let file = get_file(full_path)?;
+        let live_commands: Vec<(Vec<u8>, CommandPos)> = self
+            .index
+            .kv
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load()))
+            .filter(|(_, command_pos)| command_pos.gen < compacted_gen)
+            .collect();
+        let segment_formats: HashMap<u64, SegmentFormat> = self
+            .index
+            .segment_formats
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
 
-        serde_json::to_writer(file, &command)?;
-        // println!("Remove write complete");
+        let directory = self.directory_path.clone();
+        let index = Arc::clone(&self.index);
+        let stale_bytes = self.index.stale_bytes.load(Ordering::SeqCst);
+        let handle = thread::spawn(move || {
+            let output =
+                perform_compaction(&directory, &segment_formats, compacted_gen, sealed_gens, live_commands)?;
+            install_compaction(&directory, &index, output, stale_bytes)
+        });
 
-        self.log_pointer += 1;
+        self.compaction = Some(CompactionJob { handle });
 
         Ok(())
     }
+}
+
+impl CompactionJob {
+    ///  Wait for the compaction to be written and swapped in
+    fn wait(self) -> Result<()> {
+        self.handle
+            .join()
+            .map_err(|_| KvsError::Store("Compaction thread panicked".to_owned()))?
+    }
+}
+
+///   Swap the compacted segment in for the sealed segments it replaces and repoint the keys that were copied,
+///   reclaiming the stale bytes counted when the compaction started
+fn install_compaction(directory: &Path, index: &Index, output: CompactionOutput, stale_bytes: u64) -> Result<()> {
+    //The compacted segment only becomes visible once it is complete and on disc. Until the sealed segments
+    //are removed both are replayed on open, which yields the same state since the compacted segment sorts after them
+    fs::rename(
+        compaction_path(directory, output.compacted_gen),
+        log_path(directory, output.compacted_gen),
+    )?;
+    sync_directory(directory)?;
+
+    index.segment_formats.insert(output.compacted_gen, SegmentFormat::CURRENT);
 
-    ///Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if self.kv.get(&key).cloned().is_none() {
-            return Ok(None);
+    //Keys written or removed while the compaction ran keep their newer position: a key is only repointed
+    //if it still holds the position that was copied, checked and swapped in one step as the writer runs alongside
+    for (key, old_pos, new_pos) in output.moved.into_iter() {
+        if let Some(entry) = index.kv.get(&key) {
+            let _ = entry.value().compare_exchange(old_pos, new_pos);
         }
+    }
 
-        let log_pointer = self.kv.get(&key).unwrap();
+    //Keys whose values expired were not copied, so they are removed along with the segments they point into
+    for (key, old_pos) in output.expired.into_iter() {
+        index.remove_position_if(&key, old_pos);
+    }
 
-        let full_path = self.get_file_path();
-        // println!("set remove full path: {:?}", full_path);
+    //No key points into the sealed segments any more. A reader that looked one up before the repoint
+    //and finds its segment gone retries with the new position
+    index.safe_point.store(output.compacted_gen, Ordering::SeqCst);
 
-        // println!("Opening file on disk as part of GET");
-        // println!("full path of GET: {:?}", full_path);
+    //Oldest first, so an interrupted removal leaves only newer sealed segments behind and no removed key can reappear
+    for gen in output.sealed_gens.into_iter() {
+        remove_hint(directory, gen)?;
+        fs::remove_file(log_path(directory, gen))?;
+        index.segment_formats.remove(&gen);
+    }
+    sync_directory(directory)?;
 
-        let file = get_file(full_path)?;
+    let _ = index
+        .stale_bytes
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+            Some(current.saturating_sub(stale_bytes))
+        });
 
-        let deserialized_commands: Vec<Command> = deserialize_commands_from_file(file);
+    Ok(())
+}
 
-        // println!("Deserialized Commands from get: {:?}", deserialized_commands);
+///Lazy scan over a range of a KvStore's keys in order. Each step looks the next key up in the index afresh,
+///so the scan holds no reference into it and sees writes made while it runs
+pub struct KvStoreScan {
+    store: KvStore,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
 
-        // println!("key: {:?}, pointer value: {:?}", &key, log_pointer);
-        // println!("Store pointer value: {:?}", self.log_pointer);
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
 
-        let command_on_disc = deserialized_commands
-            .get(*log_pointer)
-            .expect("Log pointer invalid"); //TODO: Should handle this potential error better
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.store.index.first_key(self.next.as_ref())?;
+            if !before_end(&key, &self.end) {
+                return None;
+            }
+            self.next = Bound::Excluded(key.clone());
 
-        // println!("Deserialized Command found through log pointer: {:?}", command_on_disc);
+            match self.store.get_bytes(key.clone()) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                //Removed since it was looked up
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
 
-        if let Command::Set { key: _, value } = command_on_disc {
-            Ok(Some(value.to_owned()))
-        } else {
-            Err(KvsError::Store(
-                "Unable to find key through the log pointer".to_owned(),
-            ))
+impl KvsEngine for KvStore {
+    type Scan = KvStoreScan;
+
+    ///Set the value of a key. Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|writer| writer.set(key, value, None))
+    }
+
+    ///Remove a given key. Return an error if the key does not exist or is not removed successfully.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(|writer| writer.remove(key))
+    }
+
+    ///Set the value of a key along with its expiry time, which is kept in the log so it survives reopening
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(|writer| writer.set(key, value, Some(expiry_after(ttl))))
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let command_pos = self.index.position(&key).ok_or(KvsError::KeyNotFound)?;
+
+        Ok(command_pos.expires_at.map(time_left))
+    }
+
+    ///Drop expired keys from the index, going through the expiry index in order until it reaches a key that has not
+    ///expired. The log needs no removal for them, since a value found expired when it is replayed is left out anyway
+    fn remove_expired(&self) -> Result<usize> {
+        let now = now_millis();
+
+        let mut removed = 0;
+        while let Some(entry) = self.index.expiry.front() {
+            let (expires_at, key) = entry.value().clone();
+            if expires_at > now {
+                break;
+            }
+
+            if let Some(command_pos) = self.index.remove_expiring(expires_at, &key) {
+                //Segments before the safe point have been compacted away, taking the value with them
+                if command_pos.gen >= self.index.safe_point.load(Ordering::SeqCst) {
+                    self.index.stale_bytes.fetch_add(command_pos.len, Ordering::SeqCst);
+                }
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    ///Apply a batch as a single record in the log, which is replayed whole or, if a crash cut it short, not at all.
+    ///Readers on other handles may see some of its writes before the rest while it is applied
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.write(|writer| {
+            let commands: Vec<Command> = batch.into_ops().into_iter().map(Command::from).collect();
+
+            let positions = writer.append_batch(&commands)?;
+
+            //The batch's own record header is never read again once it is written
+            let mut stale_bytes = RECORD_HEADER_LENGTH as u64;
+            for (command, command_pos) in commands.into_iter().zip(positions) {
+                match command {
+                    Command::Set { key, .. } => {
+                        if let Some(old_pos) = writer.index.set_position(key, command_pos) {
+                            stale_bytes += old_pos.len;
+                        }
+                    }
+                    Command::Rm { key } => {
+                        let old_len = writer.index.remove_position(&key).map_or(0, |old_pos| old_pos.len);
+                        stale_bytes += old_len + command_pos.len;
+                    }
+                }
+            }
+            writer.index.stale_bytes.fetch_add(stale_bytes, Ordering::SeqCst);
+
+            writer.maybe_compact()?;
+
+            Ok(())
+        })
+    }
+
+    ///Compare and swap while holding the writer lock, so no other write can change the key in between
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<CasOutcome> {
+        self.write(|writer| {
+            let current = self.get_bytes(key.clone())?;
+            if current != expected {
+                return Ok(CasOutcome::Mismatch { current });
+            }
+
+            match new {
+                Some(value) => writer.set(key, value, None)?,
+                None if current.is_some() => writer.remove(key)?,
+                //Expected to be missing, and it is
+                None => {}
+            }
+
+            Ok(CasOutcome::Swapped)
+        })
+    }
+
+    ///Get the value of a key. If the key does not exist, return None. Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        loop {
+            let command_pos = match self.index.position(&key) {
+                Some(command_pos) => command_pos,
+                None => return Ok(None),
+            };
+
+            match self.read_value(&command_pos) {
+                //Compaction removed the segment after the position was looked up, so the key has moved since
+                Err(KvsError::Io(err))
+                    if err.kind() == ErrorKind::NotFound
+                        && command_pos.gen < self.index.safe_point.load(Ordering::SeqCst) => {}
+                result => return result.map(Some),
+            }
+        }
+    }
+
+    ///Iterate over a range of keys on a handle of the scan's own, so it can outlive this one
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> KvStoreScan {
+        KvStoreScan {
+            store: self.clone(),
+            next: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        }
+    }
+}
+
+impl Drop for KvStoreWriter {
+    ///Let a running compaction finish so its work is not thrown away
+    fn drop(&mut self) {
+        if let Some(job) = self.compaction.take() {
+            if let Err(err) = job.wait() {
+                warn!("Unable to finish compaction: {}", err);
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 pub enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ///Milliseconds since the Unix epoch
+        expires_at: Option<u64>,
+    },
+    Rm { key: Vec<u8> },
+}
+
+impl Command {
+    ///When the value a command sets expires, if it does
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires_at, .. } => *expires_at,
+            Command::Rm { .. } => None,
+        }
+    }
+}
+
+///A command as the JSON formats wrote it, which could only hold strings
+#[derive(Debug, Deserialize)]
+enum JsonCommand {
     Set { key: String, value: String },
     Rm { key: String },
 }
 
-///   Open a file given file path
-fn get_file(full_path: PathBuf) -> Result<File> {
-    fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .read(true)
-        .open(full_path)
-        .map_err(|err| err.into())
+impl From<BatchOp> for Command {
+    fn from(op: BatchOp) -> Command {
+        match op {
+            BatchOp::Set { key, value } => Command::Set {
+                key,
+                value,
+                expires_at: None,
+            },
+            BatchOp::Remove { key } => Command::Rm { key },
+        }
+    }
 }
 
-///   Deserialize commands from reader
-fn deserialize_commands_from_file(file: File) -> Vec<Command> {
-    serde_json::Deserializer::from_reader(file)
-        .into_iter::<Command>()
-        .flat_map(|it| it.ok())
-        .collect::<_>()
+impl From<JsonCommand> for Command {
+    fn from(command: JsonCommand) -> Command {
+        match command {
+            JsonCommand::Set { key, value } => Command::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at: None,
+            },
+            JsonCommand::Rm { key } => Command::Rm { key: key.into_bytes() },
+        }
+    }
 }
 
-///Build log pointers for active data in memory
-fn build_log_pointers(in_mem_kv: &mut KvStore, deserialized_commands: Vec<Command>) {
-    for command in deserialized_commands.iter() {
-        match command {
-            Command::Set { key, value: _ } => {
-                in_mem_kv.kv.insert(key.clone(), in_mem_kv.log_pointer);
-                in_mem_kv.log_pointer += 1;
+///   Get the file path of the segment with the given generation
+fn log_path(directory: &Path, gen: u64) -> PathBuf {
+    directory.join(format!("{}.{}", gen, LOG_FILE_EXTENSION))
+}
+
+///   Get the file path a compaction writes to before it replaces the segment with the given generation
+fn compaction_path(directory: &Path, gen: u64) -> PathBuf {
+    directory.join(format!("{}.{}", gen, COMPACTION_FILE_EXTENSION))
+}
+
+///   Get the file path of the hint file describing the segment with the given generation
+fn hint_path(directory: &Path, gen: u64) -> PathBuf {
+    directory.join(format!("{}.{}", gen, HINT_FILE_EXTENSION))
+}
+
+///   List the generations of the segments in a directory in ascending order
+fn sorted_gen_list(directory: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(directory)?
+        .flat_map(|entry| entry.map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension() == Some(OsStr::new(LOG_FILE_EXTENSION)))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+
+    gen_list.sort_unstable();
+
+    Ok(gen_list)
+}
+
+///   Flush a directory's entries to disc so that renames and removals in it survive a crash
+fn sync_directory(directory: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(directory)?.sync_all()?;
+
+    Ok(())
+}
+
+///   Stores written before segments were introduced keep everything in a single log.txt, which becomes the first segment
+fn migrate_legacy_log(directory: &Path) -> Result<()> {
+    let legacy_path = directory.join(KVS_FILE_NAME);
+
+    if legacy_path.exists() && sorted_gen_list(directory)?.is_empty() {
+        fs::rename(legacy_path, log_path(directory, 1))?;
+    }
+
+    Ok(())
+}
+
+///   Delete compacted segments that were never swapped in, e.g. because the process stopped mid-compaction
+fn remove_unfinished_compactions(directory: &Path) -> Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+
+        if path.is_file() && path.extension() == Some(OsStr::new(COMPACTION_FILE_EXTENSION)) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+///   Delete hint files whose segment no longer exists, e.g. because the process stopped mid-compaction,
+///   so they cannot be mistaken for a description of a later segment with the same generation
+fn remove_orphaned_hints(directory: &Path) -> Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+
+        if path.is_file()
+            && path.extension() == Some(OsStr::new(HINT_FILE_EXTENSION))
+            && !path.with_extension(LOG_FILE_EXTENSION).exists()
+        {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+///   Delete the hint file of a segment, if it has one
+fn remove_hint(directory: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(directory, gen)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+///   Write the hint file for a compacted segment and sync it to disc. The hint starts with a magic string, the format version,
+///   the segment's generation and length, then holds the key length, offset, length, expiry time (0 for none) and key
+///   of every record, and ends with a CRC32 checksum of everything before it
+fn write_hint(directory: &Path, gen: u64, segment_len: u64, entries: &[(Vec<u8>, CommandPos)]) -> Result<()> {
+    let mut hint = HINT_MAGIC.to_vec();
+    hint.push(HINT_FORMAT_VERSION);
+    hint.extend_from_slice(&gen.to_le_bytes());
+    hint.extend_from_slice(&segment_len.to_le_bytes());
+
+    for (key, command_pos) in entries.iter() {
+        hint.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        hint.extend_from_slice(&command_pos.offset.to_le_bytes());
+        hint.extend_from_slice(&command_pos.len.to_le_bytes());
+        hint.extend_from_slice(&command_pos.expires_at.unwrap_or(0).to_le_bytes());
+        hint.extend_from_slice(key);
+    }
+
+    let checksum = crc32fast::hash(&hint);
+    hint.extend_from_slice(&checksum.to_le_bytes());
+
+    let mut file = File::create(hint_path(directory, gen))?;
+    file.write_all(&hint)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+///   Read the keys and positions of a segment from its hint file. Return None when there is no hint, or when it does not
+///   match the segment and is removed so the segment is replayed instead
+fn load_hint(directory: &Path, gen: u64) -> Result<Option<HintEntries>> {
+    let path = hint_path(directory, gen);
+
+    let hint = match fs::read(&path) {
+        Ok(hint) => hint,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let segment_len = fs::metadata(log_path(directory, gen))?.len();
+
+    match parse_hint(&hint, gen, segment_len) {
+        Some(entries) => Ok(Some(entries)),
+        None => {
+            warn!("Ignoring invalid hint file {}", path.display());
+            fs::remove_file(&path)?;
+            Ok(None)
+        }
+    }
+}
+
+///   Decode a hint file, checking its checksum and that it describes the segment with the given generation and length
+fn parse_hint(hint: &[u8], gen: u64, segment_len: u64) -> Option<HintEntries> {
+    let read_u64 = |bytes: &[u8]| -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&bytes[..8]);
+        u64::from_le_bytes(buf)
+    };
+
+    let header_len = HINT_MAGIC.len() + 1 + 16;
+    if hint.len() < header_len + 4 {
+        return None;
+    }
+
+    let (body, checksum) = hint.split_at(hint.len() - 4);
+    if crc32fast::hash(body) != read_u32(checksum)
+        || !body.starts_with(HINT_MAGIC)
+        || body[HINT_MAGIC.len()] != HINT_FORMAT_VERSION
+        || read_u64(&body[HINT_MAGIC.len() + 1..]) != gen
+        || read_u64(&body[HINT_MAGIC.len() + 9..]) != segment_len
+    {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut rest = &body[header_len..];
+    while !rest.is_empty() {
+        if rest.len() < HINT_ENTRY_HEADER_LENGTH {
+            return None;
+        }
+
+        let key_len = read_u32(rest) as usize;
+        let offset = read_u64(&rest[4..]);
+        let len = read_u64(&rest[12..]);
+        let expires_at = Some(read_u64(&rest[20..])).filter(|expires_at| *expires_at != 0);
+        rest = &rest[HINT_ENTRY_HEADER_LENGTH..];
+
+        if rest.len() < key_len || offset.checked_add(len)? > segment_len {
+            return None;
+        }
+
+        let key = rest[..key_len].to_vec();
+        rest = &rest[key_len..];
+
+        entries.push((
+            key,
+            CommandPos {
+                gen,
+                offset,
+                len,
+                expires_at,
+            },
+        ));
+    }
+
+    Some(entries)
+}
+
+///   The header written at the start of every segment: a magic string and the format version
+fn segment_header() -> Vec<u8> {
+    let mut header = SEGMENT_MAGIC.to_vec();
+    header.push(LOG_FORMAT_VERSION);
+    header
+}
+
+///   Encode a command as a binary record: a CRC32 checksum of the rest of the record, the op type,
+///   the key and value lengths, then the raw key and value bytes. A value that expires is preceded by its expiry time
+fn encode_record(command: &Command) -> Result<Vec<u8>> {
+    match command {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+        } => encode_fields(SET_RECORD, key, value),
+        Command::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        } => {
+            let mut expiring_value = Vec::with_capacity(EXPIRY_LENGTH + value.len());
+            expiring_value.extend_from_slice(&expires_at.to_le_bytes());
+            expiring_value.extend_from_slice(value);
+            encode_fields(SET_WITH_EXPIRY_RECORD, key, &expiring_value)
+        }
+        Command::Rm { key } => encode_fields(RM_RECORD, key, &[]),
+    }
+}
+
+///   The offset and length of a record
+type RecordSpan = (u64, u64);
+
+///   Encode a batch of commands as a single record with the batch op, no key, and the records of the commands
+///   as its value, so one checksum covers all of them. Return it along with the offset and length of each
+///   command's record within it
+fn encode_batch(commands: &[Command]) -> Result<(Vec<u8>, Vec<RecordSpan>)> {
+    let mut records = Vec::new();
+    let mut positions = Vec::with_capacity(commands.len());
+
+    for command in commands.iter() {
+        let record = encode_record(command)?;
+        positions.push(((RECORD_HEADER_LENGTH + records.len()) as u64, record.len() as u64));
+        records.extend_from_slice(&record);
+    }
+
+    Ok((encode_fields(BATCH_RECORD, &[], &records)?, positions))
+}
+
+///   Lay out a binary record with its checksum. Keys and values longer than a u32 length field can hold are rejected
+fn encode_fields(op: u8, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    let key_len = field_length(key, "Key")?;
+    let value_len = field_length(value, "Value")?;
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + key.len() + value.len());
+    record.extend_from_slice(&[0; 4]);
+    record.push(op);
+    record.extend_from_slice(&key_len.to_le_bytes());
+    record.extend_from_slice(&value_len.to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+
+    let checksum = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&checksum.to_le_bytes());
+
+    Ok(record)
+}
+
+///   The length of a record field as stored in the record header
+fn field_length(field: &[u8], name: &str) -> Result<u32> {
+    u32::try_from(field.len()).map_err(|_| {
+        KvsError::Store(format!(
+            "{} of {} bytes is too long for a log record",
+            name,
+            field.len()
+        ))
+    })
+}
+
+///   Read a little endian u32 from the start of a slice
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+///   Read the record at the reader's position from a segment with the given format, where `remaining` bytes are left in the segment
+fn read_record(reader: &mut impl Read, format: SegmentFormat, remaining: u64) -> Result<RecordRead> {
+    match format {
+        SegmentFormat::Legacy => Err(KvsError::Store(
+            "Legacy segments do not have records".to_owned(),
+        )),
+        SegmentFormat::JsonFrames => {
+            if remaining < FRAME_HEADER_LENGTH as u64 {
+                return Ok(RecordRead::Torn);
+            }
+
+            let mut header = [0; FRAME_HEADER_LENGTH];
+            reader.read_exact(&mut header)?;
+            let len = FRAME_HEADER_LENGTH as u64 + read_u32(&header[..4]) as u64;
+            let checksum = read_u32(&header[4..]);
+
+            if len > remaining {
+                return Ok(RecordRead::Torn);
+            }
+
+            let mut serialized_command = vec![0; len as usize - FRAME_HEADER_LENGTH];
+            reader.read_exact(&mut serialized_command)?;
+
+            match serde_json::from_slice::<JsonCommand>(&serialized_command) {
+                Ok(command) if crc32fast::hash(&serialized_command) == checksum => {
+                    Ok(RecordRead::Command(command.into(), len))
+                }
+                _ => Ok(RecordRead::Invalid(len)),
+            }
+        }
+        SegmentFormat::Binary(version) => {
+            if remaining < RECORD_HEADER_LENGTH as u64 {
+                return Ok(RecordRead::Torn);
+            }
+
+            let mut record = vec![0; RECORD_HEADER_LENGTH];
+            reader.read_exact(&mut record)?;
+            let key_len = read_u32(&record[5..9]) as usize;
+            let value_len = read_u32(&record[9..13]) as usize;
+            let len = (RECORD_HEADER_LENGTH + key_len + value_len) as u64;
+
+            if len > remaining {
+                return Ok(RecordRead::Torn);
+            }
+
+            record.resize(len as usize, 0);
+            reader.read_exact(&mut record[RECORD_HEADER_LENGTH..])?;
+
+            if crc32fast::hash(&record[4..]) != read_u32(&record[..4]) {
+                return Ok(RecordRead::Invalid(len));
+            }
+
+            //A whole record with a valid checksum was written as it is, so an op this version does not know of
+            //is not a torn write but data that cannot be read
+            let op = record[4];
+            if op_format_version(op).is_none_or(|introduced| introduced > version) {
+                return Err(KvsError::Store(format!(
+                    "Unknown record op {} in a log format version {} segment",
+                    op, version
+                )));
+            }
+
+            let mut value = record.split_off(RECORD_HEADER_LENGTH + key_len);
+            let key = record.split_off(RECORD_HEADER_LENGTH);
+
+            let command = match op {
+                SET_RECORD => Command::Set {
+                    key,
+                    value,
+                    expires_at: None,
+                },
+                SET_WITH_EXPIRY_RECORD if value.len() >= EXPIRY_LENGTH => {
+                    let value_only = value.split_off(EXPIRY_LENGTH);
+                    let mut expires_at = [0; EXPIRY_LENGTH];
+                    expires_at.copy_from_slice(&value);
+                    Command::Set {
+                        key,
+                        value: value_only,
+                        expires_at: Some(u64::from_le_bytes(expires_at)),
+                    }
+                }
+                RM_RECORD if value.is_empty() => Command::Rm { key },
+                BATCH_RECORD if key.is_empty() => {
+                    return Ok(match decode_batch(&value, format)? {
+                        Some(commands) => RecordRead::Batch(commands, len),
+                        None => RecordRead::Invalid(len),
+                    })
+                }
+                _ => return Ok(RecordRead::Invalid(len)),
+            };
+
+            Ok(RecordRead::Command(command, len))
+        }
+    }
+}
+
+///   Decode the records of the commands in a batch from a segment with the given format, with the offset of each within
+///   the batch's record and its length. Return None if any of them is not a valid command
+fn decode_batch(records: &[u8], format: SegmentFormat) -> Result<Option<Vec<(Command, u64, u64)>>> {
+    let mut commands = Vec::new();
+    let mut offset = 0;
+
+    while offset < records.len() {
+        let mut rest = &records[offset..];
+        let remaining = rest.len() as u64;
+        match read_record(&mut rest, format, remaining)? {
+            RecordRead::Command(command, len) => {
+                commands.push((command, (RECORD_HEADER_LENGTH + offset) as u64, len));
+                offset += len as usize;
+            }
+            _ => return Ok(None),
+        }
+    }
+
+    Ok(Some(commands))
+}
+
+///   The log format version a record op was introduced in, or None if it is not one this version knows of
+fn op_format_version(op: u8) -> Option<u8> {
+    match op {
+        SET_RECORD | RM_RECORD => Some(BINARY_FORMAT_VERSION),
+        BATCH_RECORD => Some(BATCH_FORMAT_VERSION),
+        SET_WITH_EXPIRY_RECORD => Some(EXPIRY_FORMAT_VERSION),
+        _ => None,
+    }
+}
+
+///   Read the command a pointer refers to from a reader positioned at it, verifying its checksum
+fn read_command(reader: &mut impl Read, format: SegmentFormat, command_pos: &CommandPos) -> Result<Command> {
+    if format == SegmentFormat::Legacy {
+        return serde_json::from_reader::<_, JsonCommand>(reader.take(command_pos.len))
+            .map(Command::from)
+            .map_err(|err| err.into());
+    }
+
+    match read_record(reader, format, command_pos.len)? {
+        RecordRead::Command(command, len) if len == command_pos.len => Ok(command),
+        _ => Err(KvsError::Corruption(format!(
+            "Invalid command in segment {} at offset {}",
+            command_pos.gen, command_pos.offset
+        ))),
+    }
+}
+
+///   Deserialize the commands of a segment into the index updates they make, recording the byte position of each command.
+///   A batch's commands are kept together so they are replayed as one. A record cut short at the end of the newest segment, with nothing valid after it, is a write that was interrupted by a crash and is truncated away,
+///   while a record that is cut short or fails its checksum anywhere else means the segment is corrupt.
+fn deserialize_index_updates_from_file(
+    directory: &Path,
+    gen: u64,
+    newest: bool,
+) -> Result<(SegmentFormat, Vec<IndexUpdate>)> {
+    let path = log_path(directory, gen);
+    let file = File::open(&path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let header = segment_header();
+    let mut header_on_disc = Vec::with_capacity(header.len());
+    (&mut reader).take(header.len() as u64).read_to_end(&mut header_on_disc)?;
+
+    let format = if header_on_disc.len() < header.len() {
+        //A header cut short belongs to a new segment that has nothing in it yet
+        if !header.starts_with(&header_on_disc) {
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok((SegmentFormat::Legacy, deserialize_legacy_updates(reader, gen)));
+        }
+        SegmentFormat::CURRENT
+    } else if header_on_disc.starts_with(SEGMENT_MAGIC) {
+        let version = header_on_disc[SEGMENT_MAGIC.len()];
+        SegmentFormat::from_version(version).ok_or_else(|| {
+            KvsError::Store(format!(
+                "Unsupported log format version {} in {}",
+                version,
+                path.display()
+            ))
+        })?
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+        return Ok((SegmentFormat::Legacy, deserialize_legacy_updates(reader, gen)));
+    };
+
+    let mut updates = Vec::new();
+    let mut offset = header_on_disc.len() as u64;
+
+    let valid_len = if header_on_disc.len() < header.len() {
+        0
+    } else {
+        loop {
+            if offset == file_len {
+                break offset;
             }
-            Command::Rm { key } => {
-                in_mem_kv.kv.remove(key);
-                in_mem_kv.log_pointer += 1;
+
+            match read_record(&mut reader, format, file_len - offset)? {
+                RecordRead::Command(command, len) => {
+                    let command_pos = CommandPos {
+                        gen,
+                        offset,
+                        len,
+                        expires_at: None,
+                    };
+                    updates.push(IndexUpdate::from_command(command, command_pos));
+                    offset += len;
+                }
+                RecordRead::Batch(commands, len) => {
+                    let batch_offset = offset;
+                    updates.push(IndexUpdate::Batch(
+                        commands
+                            .into_iter()
+                            .map(|(command, offset, len)| {
+                                let command_pos = CommandPos {
+                                    gen,
+                                    offset: batch_offset + offset,
+                                    len,
+                                    expires_at: None,
+                                };
+                                IndexUpdate::from_command(command, command_pos)
+                            })
+                            .collect(),
+                    ));
+                    offset += len;
+                }
+                //Only the last record of the newest segment can have been torn by a crash
+                RecordRead::Torn if newest && !valid_records_follow(&path, format, offset)? => break offset,
+                RecordRead::Invalid(len)
+                    if newest && offset + len == file_len && !valid_records_follow(&path, format, offset)? =>
+                {
+                    break offset
+                }
+                RecordRead::Torn | RecordRead::Invalid(_) => {
+                    return Err(KvsError::Corruption(format!(
+                        "Invalid command in {} at offset {}",
+                        path.display(),
+                        offset
+                    )))
+                }
+            }
+        }
+    };
+
+    if valid_len < file_len {
+        warn!("Truncating torn write at the end of {} from offset {}", path.display(), valid_len);
+
+        let file = fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_len(valid_len)?;
+        file.sync_all()?;
+    }
+
+    Ok((format, updates))
+}
+
+///   Whether whole, valid records run from somewhere inside the record at `offset` right up to the end of a segment.
+///   A crash only leaves the start of the record being written behind, so a record that seems cut short or fails its
+///   checksum with valid records after it had its lengths damaged rather than being torn
+fn valid_records_follow(path: &Path, format: SegmentFormat, offset: u64) -> Result<bool> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+
+    //A batch cut short between two of its commands leaves whole records behind, which are its own
+    let batch_commands = batch_command_offsets(&tail, format);
+
+    Ok((1..tail.len())
+        .filter(|start| !batch_commands.contains(start))
+        .any(|start| records_reach_end(&tail[start..], format)))
+}
+
+///   Offsets within `record` of the commands that start a batch record, as far as they are whole and valid
+fn batch_command_offsets(record: &[u8], format: SegmentFormat) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    if !matches!(format, SegmentFormat::Binary(_))
+        || record.len() < RECORD_HEADER_LENGTH
+        || record[4] != BATCH_RECORD
+    {
+        return offsets;
+    }
+
+    let mut offset = RECORD_HEADER_LENGTH;
+    while offset < record.len() {
+        let mut rest = &record[offset..];
+        let remaining = rest.len() as u64;
+        match read_record(&mut rest, format, remaining) {
+            Ok(RecordRead::Command(_, len)) => {
+                offsets.push(offset);
+                offset += len as usize;
+            }
+            _ => break,
+        }
+    }
+
+    offsets
+}
+
+///   Whether `records` holds nothing but whole, valid records
+fn records_reach_end(mut records: &[u8], format: SegmentFormat) -> bool {
+    while !records.is_empty() {
+        let remaining = records.len() as u64;
+        if !matches!(
+            read_record(&mut records, format, remaining),
+            Ok(RecordRead::Command(..) | RecordRead::Batch(..))
+        ) {
+            return false;
+        }
+    }
+
+    true
+}
+
+///   Deserialize the bare commands of a legacy segment into the index updates they make, recording the byte position of each command
+fn deserialize_legacy_updates(reader: impl Read, gen: u64) -> Vec<IndexUpdate> {
+    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<JsonCommand>();
+    let mut updates = Vec::new();
+    let mut offset = stream.byte_offset() as u64;
+
+    //Legacy segments carry no checksums, so reading stops at the first command that fails to parse
+    while let Some(Ok(command)) = stream.next() {
+        let new_offset = stream.byte_offset() as u64;
+        updates.push(IndexUpdate::from_command(
+            command.into(),
+            CommandPos {
+                gen,
+                offset,
+                len: new_offset - offset,
+                expires_at: None,
+            },
+        ));
+        offset = new_offset;
+    }
+
+    updates
+}
+
+///Build log pointers for active data in memory. Return the bytes of every command that has since been superseded.
+///A batch only gets here if its whole record was read back, so its updates are applied all together.
+///A value that has expired by `now` removes the key like a removal would
+fn build_log_pointers(kv: &mut HashMap<Vec<u8>, CommandPos>, index_updates: Vec<IndexUpdate>, now: u64) -> u64 {
+    let mut stale_bytes = 0;
+
+    for index_update in index_updates.into_iter() {
+        match index_update {
+            IndexUpdate::Set(key, command_pos) if command_pos.expires_at.is_some_and(|expires_at| expires_at <= now) => {
+                if let Some(old_pos) = kv.remove(&key) {
+                    stale_bytes += old_pos.len;
+                }
+                stale_bytes += command_pos.len;
+            }
+            IndexUpdate::Set(key, command_pos) => {
+                if let Some(old_pos) = kv.insert(key, command_pos) {
+                    stale_bytes += old_pos.len;
+                }
+            }
+            IndexUpdate::Rm(key, command_pos) => {
+                if let Some(old_pos) = kv.remove(&key) {
+                    stale_bytes += old_pos.len;
+                }
+                stale_bytes += command_pos.len;
+            }
+            IndexUpdate::Batch(updates) => {
+                stale_bytes += RECORD_HEADER_LENGTH as u64 + build_log_pointers(kv, updates, now);
             }
         };
     }
+
+    stale_bytes
 }
 
-///Perform compaction given a KvStore
+///Perform compaction of the sealed segments of a KvStore.
+///The live commands that have not expired are copied into a temporary file which is synced to disc, along with a hint file describing it, before returning.
+///The caller renames it into place as the compacted segment and removes the sealed segments.
 fn perform_compaction(
-    in_mem_kv: &mut KvStore,
-    deserialized_commands: Vec<Command>,
-    new_disc: &mut Vec<Command>,
-) {
-    //track number of removals
-    let mut removals: usize = 0;
-
-    //For (i, command) in deserialized_commands.enumerate()
-    //look up command.key in memory hashmap
-    //if exits and positon i is equal to hashmap pointer value, then copy to new vec<Command> and set in memory pointer value as (current value minus the removals so far)
-    //else increment removal counter by one
-    //(Note: if does not exist or exists but position i is less than the hashmap pointer value, then disregard for removal)
-
-    for (i, command) in deserialized_commands.iter().enumerate() {
-        match command {
-            Command::Rm { key: _ } => continue,
-            Command::Set { key, value: _ } => {
-                let pointer = in_mem_kv.kv.get(key);
-
-                if pointer != None && *pointer.unwrap() == i {
-                    new_disc.push(command.to_owned());
-                    in_mem_kv.kv.insert(key.to_string(), i - removals);
-                } else {
-                    removals += 1;
-                }
+    directory: &Path,
+    segment_formats: &HashMap<u64, SegmentFormat>,
+    compacted_gen: u64,
+    sealed_gens: Vec<u64>,
+    live_commands: Vec<(Vec<u8>, CommandPos)>,
+) -> Result<CompactionOutput> {
+    //Expired values are dropped, and their keys are removed from the index when the compaction is installed
+    let (expired, mut live_commands): (Vec<_>, Vec<_>) = live_commands
+        .into_iter()
+        .partition(|(_, command_pos)| command_pos.is_expired());
+
+    //Copy segment by segment so each one is read front to back
+    live_commands.sort_unstable_by_key(|(_, command_pos)| (command_pos.gen, command_pos.offset));
+
+    let mut writer = BufWriter::new(File::create(compaction_path(directory, compacted_gen))?);
+    let mut readers: HashMap<u64, BufReader<File>> = HashMap::new();
+    let mut moved = Vec::with_capacity(live_commands.len());
+
+    let header = segment_header();
+    writer.write_all(&header)?;
+    let mut new_offset = header.len() as u64;
+
+    for (key, command_pos) in live_commands.into_iter() {
+        let reader = match readers.get_mut(&command_pos.gen) {
+            Some(reader) => reader,
+            None => {
+                let file = File::open(log_path(directory, command_pos.gen))?;
+                readers.entry(command_pos.gen).or_insert(BufReader::new(file))
             }
         };
+        reader.seek(SeekFrom::Start(command_pos.offset))?;
+
+        //Checksums are verified on the way so corruption is not carried into the compacted segment,
+        //and commands from segments in older formats are rewritten in the current one
+        let format = segment_formats.get(&command_pos.gen).copied().unwrap_or(SegmentFormat::CURRENT);
+        let record = encode_record(&read_command(reader, format, &command_pos)?)?;
+        writer.write_all(&record)?;
+
+        let new_pos = CommandPos {
+            gen: compacted_gen,
+            offset: new_offset,
+            len: record.len() as u64,
+            expires_at: command_pos.expires_at,
+        };
+        moved.push((key, command_pos, new_pos));
+        new_offset += new_pos.len;
     }
 
-    //reset pointer value
-    in_mem_kv.log_pointer = new_disc.len();
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    let hint_entries: HintEntries = moved
+        .iter()
+        .map(|(key, _, new_pos)| (key.clone(), *new_pos))
+        .collect();
+    write_hint(directory, compacted_gen, new_offset, &hint_entries)?;
+
+    Ok(CompactionOutput {
+        compacted_gen,
+        sealed_gens,
+        moved,
+        expired,
+    })
 }
@@ -0,0 +1,71 @@
+use crate::error::{KvsError, Result};
+use crate::utils::LOCK_FILE_NAME;
+use fs2::FileExt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+///An exclusive advisory lock on a data directory, held until it is dropped
+#[derive(Debug)]
+pub(crate) struct DirectoryLock {
+    file: File,
+}
+
+impl DirectoryLock {
+    ///Lock a data directory, creating it and its lock file if needed. Return an error if another handle holds the lock
+    pub(crate) fn acquire(directory: &Path) -> Result<DirectoryLock> {
+        fs::create_dir_all(directory)?;
+
+        let path = directory.join(LOCK_FILE_NAME);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        file.try_lock_exclusive().map_err(|err| {
+            if is_contended(&err) {
+                KvsError::StoreInUse(directory.display().to_string())
+            } else {
+                KvsError::Io(err)
+            }
+        })?;
+
+        Ok(DirectoryLock { file })
+    }
+}
+
+///Wait for whoever holds the exclusive lock on a file to release it, releasing it again straight away.
+///Return KvsError::StoreInUse if it is still held after the timeout. A file that does not exist is not locked
+pub(crate) fn wait_for_file_lock(path: &Path, timeout: Duration) -> Result<()> {
+    let file = match fs::OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(FileExt::unlock(&file)?),
+            Err(err) if is_contended(&err) && Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Err(err) if is_contended(&err) => return Err(KvsError::StoreInUse(path.display().to_string())),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+///   Whether locking failed because another handle holds the lock
+fn is_contended(err: &io::Error) -> bool {
+    err.kind() == fs2::lock_contended_error().kind()
+}
+
+impl Drop for DirectoryLock {
+    ///Release the lock; closing the file would release it as well
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
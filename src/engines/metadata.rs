@@ -0,0 +1,110 @@
+use super::KvStore;
+use crate::error::{KvsError, Result};
+use crate::utils::{KVS_CODE, LOG_FORMAT_VERSION, META_FILE_NAME, SLED_CODE, SLED_FILE_NAME, SLED_FORMAT_VERSION};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///What a data directory holds, recorded in its META file so the engine does not have to be guessed from file names
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    ///Engine the data was written by, kvs or sled
+    pub engine: String,
+    ///Version of the engine's on-disc format. 0 for data written before the META file was introduced
+    pub format_version: u8,
+    ///Seconds since the Unix epoch at which the directory was first opened
+    pub created_at: u64,
+}
+
+impl Metadata {
+    ///Check that a data directory can be opened with the given engine. Directories without a META file, written
+    ///before it was introduced, are recognised by the engine's files instead. Return the metadata to store once the engine is open
+    pub fn verify(data_dir: &Path, engine: &str) -> Result<Metadata> {
+        let current_version = format_version(engine)?;
+
+        let metadata = match fs::read(meta_path(data_dir)) {
+            Ok(bytes) => serde_json::from_slice::<Metadata>(&bytes).map_err(|err| {
+                KvsError::Metadata(format!("{} is unreadable: {}", meta_path(data_dir).display(), err))
+            })?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Metadata {
+                engine: detect_engine(data_dir).unwrap_or(engine).to_owned(),
+                format_version: 0,
+                created_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs()),
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        if metadata.engine != engine {
+            return Err(KvsError::EngineMismatch(format!(
+                "{} holds {} data and cannot be opened with the {} engine",
+                data_dir.display(),
+                metadata.engine,
+                engine
+            )));
+        }
+
+        if metadata.format_version > current_version {
+            return Err(KvsError::Metadata(format!(
+                "{} holds {} format version {}, but this version of kvs only reads up to {}",
+                data_dir.display(),
+                metadata.engine,
+                metadata.format_version,
+                current_version
+            )));
+        }
+
+        Ok(metadata)
+    }
+
+    ///Record in the META file that the directory now holds data in the engine's current format. Called once the engine
+    ///has opened the directory, which upgrades data in older formats
+    pub fn store(mut self, data_dir: &Path) -> Result<()> {
+        let stored = self.clone();
+        self.format_version = format_version(&self.engine)?;
+
+        if self == stored && meta_path(data_dir).exists() {
+            return Ok(());
+        }
+
+        //Written alongside and renamed over the old file, so a crash never leaves a partial META file behind
+        let temporary_path = data_dir.join(format!("{}.tmp", META_FILE_NAME));
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(&serde_json::to_vec(&self)?)?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, meta_path(data_dir))?;
+
+        #[cfg(unix)]
+        File::open(data_dir)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+///   Get the path of the META file in a data directory
+fn meta_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(META_FILE_NAME)
+}
+
+///   The on-disc format version the given engine writes
+fn format_version(engine: &str) -> Result<u8> {
+    match engine.as_bytes() {
+        KVS_CODE => Ok(LOG_FORMAT_VERSION),
+        SLED_CODE => Ok(SLED_FORMAT_VERSION),
+        _ => Err(KvsError::CommandError("Engine not found".to_string())),
+    }
+}
+
+///   Recognise the engine of a data directory written before the META file was introduced by the files it holds
+fn detect_engine(data_dir: &Path) -> Option<&'static str> {
+    if data_dir.join(SLED_FILE_NAME).exists() {
+        Some("sled")
+    } else if KvStore::log_exists(data_dir) {
+        Some("kvs")
+    } else {
+        None
+    }
+}
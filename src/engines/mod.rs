@@ -1,14 +1,129 @@
 use crate::error::Result;
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+///A key value store that can be cloned and shared between threads, with every clone referring to the same data.
+///Keys and values are arbitrary bytes; the string methods are conveniences on top of the byte ones
+pub trait KvsEngine: Clone + Send + 'static {
+    ///Lazy iterator over key/value pairs in key order, as returned by a scan
+    type Scan: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>;
 
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
 
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    ///Set the value of a key for a limited time, after which it reads as missing until it is removed for good
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    ///Time left before a key expires, or None if it never does. Return KvsError::KeyNotFound if it does not exist
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    ///Remove every key that has expired. Return how many were removed
+    fn remove_expired(&self) -> Result<usize>;
+
+    ///Apply every write in a batch, so that after a crash either all of them or none of them have happened
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    ///Set a key to `new`, or remove it if `new` is None, only if its value is `expected`, where None means it has
+    ///none. No other write to the store comes between the comparison and the swap
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<CasOutcome>;
+
+    ///Set a key only if it has no value. Return whether it was set
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        Ok(self.compare_and_swap(key, None, Some(value))?.is_swapped())
+    }
+
+    ///Remove a key only if its value is the one given. Return whether it was removed
+    fn remove_if_equals(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        Ok(self.compare_and_swap(key, Some(value), None)?.is_swapped())
+    }
+
+    ///Iterate over the keys in a range in order, with their values. Keys are read as the iterator advances
+    ///rather than all at once
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Self::Scan;
+
+    ///Iterate over the keys starting with a prefix in order, with their values
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Self::Scan {
+        self.scan(prefix_range(prefix))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    ///Get the value of a key as a string. Return an error if the value is not valid UTF-8
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+}
+
+///The range of keys starting with a prefix: from the prefix itself up to, but not including, the first key past
+///every key it starts
+pub fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+
+    //Every byte is 0xff, so no key past the prefix is without it
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+///   Milliseconds since the Unix epoch, the unit expiry times are kept in
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+///   The expiry time of a value set now to live for the given time
+fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
+///   Whether a value with the given expiry time has expired
+fn is_expired(expires_at: u64) -> bool {
+    expires_at <= now_millis()
+}
+
+///   Time left until an expiry time
+fn time_left(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now_millis()))
+}
+
+///   Whether a key comes before the end of a range
+fn before_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    }
 }
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::async_engine::AsyncKvsEngine;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::cas::CasOutcome;
+pub use self::durability::Durability;
+pub use self::kvs::{KvStore, KvStoreOptions, KvStoreScan};
+pub use self::metadata::Metadata;
+pub use self::sled::{SledKvsEngine, SledScan};
 
+mod async_engine;
+mod batch;
+mod cas;
+mod durability;
 mod kvs;
+mod lock;
+mod metadata;
 mod sled;
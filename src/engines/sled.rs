@@ -1,47 +1,299 @@
-use super::KvsEngine;
+use super::lock::{wait_for_file_lock, DirectoryLock};
+use super::metadata::Metadata;
+use super::{before_end, expiry_after, is_expired, time_left, BatchOp, CasOutcome, Durability, KvsEngine, WriteBatch};
 use crate::error::{KvsError, Result};
+use crate::utils::{EXPIRY_LENGTH, SLED_DB_FILE_NAME, SLED_EXPIRY_TREE, SLED_FILE_NAME, SLED_RELEASE_TIMEOUT_SECS};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionalTree,
+    UnabortableTransactionError,
+};
+use sled::{IVec, Transactional};
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+#[derive(Clone)]
 pub struct SledKvsEngine {
     pub directory_path: PathBuf,
     pub sled_db: sled::Db,
+    pub durability: Durability,
+    ///Expiry time of every key that has one, in milliseconds since the Unix epoch, kept in a tree of its own
+    ///so the values are stored exactly as they were given
+    expiry: sled::Tree,
+    _lock: Arc<DirectoryLock>,
 }
 
 impl SledKvsEngine {
-    pub fn open(name: &str) -> Result<sled::Db> {
-        sled::open(name).map_err(|err| err.into())
+    ///Open the sled engine in a data directory, keeping sled's own files in a directory within it and recording the
+    ///engine in the data directory's META file. The database is flushed in the background as often as the durability
+    ///asks for. Return an error if another handle has it open or the directory holds another engine's data
+    pub fn open(path: impl Into<PathBuf>, durability: Durability) -> Result<SledKvsEngine> {
+        let directory_path: PathBuf = path.into();
+        let lock = DirectoryLock::acquire(&directory_path)?;
+        let metadata = Metadata::verify(&directory_path, "sled")?;
+
+        let sled_path = directory_path.join(SLED_FILE_NAME);
+        let config = sled::Config::new().path(&sled_path);
+
+        let config = match durability {
+            Durability::EveryMs(ms) => config.flush_every_ms(Some(ms)),
+            _ => config,
+        };
+
+        //sled's background threads keep its own lock on its database file for a moment after a database is dropped.
+        //Nothing else can take that lock while the directory lock is held, so a handle reopening it straight away
+        //waits for them to let go rather than failing
+        wait_for_file_lock(
+            &sled_path.join(SLED_DB_FILE_NAME),
+            Duration::from_secs(SLED_RELEASE_TIMEOUT_SECS),
+        )?;
+        let sled_db = config.open()?;
+
+        let expiry = sled_db.open_tree(SLED_EXPIRY_TREE)?;
+
+        metadata.store(&directory_path)?;
+
+        Ok(SledKvsEngine {
+            directory_path,
+            sled_db,
+            durability,
+            expiry,
+            _lock: Arc::new(lock),
+        })
+    }
+
+    ///Flush the database before a write is acknowledged unless it is flushed periodically. sled keeps unflushed
+    ///writes in its own buffers rather than the operating system's, where they would be lost with the process, and
+    ///flushing is the only way to hand them on, so os-buffered flushes every write as well to match the kvs engine
+    fn sync(&self) -> Result<()> {
+        if !matches!(self.durability, Durability::EveryMs(_)) {
+            self.sled_db.flush()?;
+        }
+
+        Ok(())
+    }
+
+    ///  Run a transaction over the values and their expiry times, so a value is never written apart from its expiry
+    fn transaction<T>(
+        &self,
+        f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvsError>,
+    ) -> Result<T> {
+        (&*self.sled_db, &self.expiry)
+            .transaction(|(values, expiry)| f(values, expiry))
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => err.into(),
+            })
+    }
+
+    ///  Whether a key has expired. The expiry is read before the value, so a value written in between is
+    ///  never mistaken for an expired one that was replaced
+    fn has_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(decode_expiry(self.expiry.get(key)?).is_some_and(is_expired))
+    }
+}
+
+///   The value of a key within a transaction, unless it has expired
+fn live_value(
+    values: &TransactionalTree,
+    expiry: &TransactionalTree,
+    key: &[u8],
+) -> std::result::Result<Option<IVec>, UnabortableTransactionError> {
+    if decode_expiry(expiry.get(key)?).is_some_and(is_expired) {
+        return Ok(None);
+    }
+
+    values.get(key)
+}
+
+///   Decode an expiry time as stored in the expiry tree
+fn decode_expiry(expires_at: Option<IVec>) -> Option<u64> {
+    let expires_at: [u8; EXPIRY_LENGTH] = expires_at?.as_ref().try_into().ok()?;
+
+    Some(u64::from_be_bytes(expires_at))
+}
+
+///Lazy scan over a range of a sled database's keys in order, skipping keys that have expired
+pub struct SledScan {
+    iter: sled::Iter,
+    end: Bound<Vec<u8>>,
+    expiry: sled::Tree,
+}
+
+impl Iterator for SledScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next()? {
+                //The end is checked here rather than handed to sled, so a range that ends before it starts is empty
+                Ok((key, _)) if !before_end(&key, &self.end) => return None,
+                Ok((key, value)) => match self.expiry.get(&key) {
+                    Ok(expires_at) => {
+                        if !decode_expiry(expires_at).is_some_and(is_expired) {
+                            return Some(Ok((key.to_vec(), value.to_vec())));
+                        }
+                    }
+                    Err(err) => return Some(Err(err.into())),
+                },
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let _result = self.sled_db.insert(key.as_bytes(), value.as_bytes());
+    type Scan = SledScan;
 
-        Ok(())
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.transaction(|values, expiry| {
+            values.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })?;
+
+        self.sync()
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let ivec_value = self.sled_db.get(key.as_bytes())?; //TODO! Better error handling for option
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if self.has_expired(&key)? {
+            return Ok(None);
+        }
 
-        if ivec_value.is_none() {
-            return Ok(Some("Key not found".to_string()));
-        };
+        Ok(self.sled_db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.transaction(|values, expiry| {
+            if live_value(values, expiry, &key)?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+            }
+            values.remove(key.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })?;
 
-        //TODO! Is there a better way to convert Ivecs into Strings?
-        let vec_bytes: Vec<u8> = ivec_value.unwrap().iter().map(|byte| *byte).collect();
+        self.sync()
+    }
+
+    ///Set the value of a key along with its expiry time in the expiry tree, in a single transaction
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_after(ttl).to_be_bytes();
 
-        let string = String::from_utf8_lossy(&vec_bytes);
+        self.transaction(|values, expiry| {
+            values.insert(key.as_slice(), value.as_slice())?;
+            expiry.insert(key.as_slice(), &expires_at[..])?;
+            Ok(())
+        })?;
 
-        Ok(Some(string.to_string()))
+        self.sync()
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let result = self.sled_db.remove(key.as_bytes())?;
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let expires_at = decode_expiry(self.expiry.get(&key)?);
 
-        if result.is_none() {
-            return Err(KvsError::Store("Key not found".to_owned()));
+        if expires_at.is_some_and(is_expired) || !self.sled_db.contains_key(&key)? {
+            return Err(KvsError::KeyNotFound);
         }
 
-        Ok(())
+        Ok(expires_at.map(time_left))
+    }
+
+    ///Remove every key the expiry tree lists as expired, checking again within the transaction that removes it
+    ///in case it was set anew in the meantime
+    fn remove_expired(&self) -> Result<usize> {
+        let mut removed = 0;
+
+        for entry in self.expiry.iter() {
+            let (key, expires_at) = entry?;
+            if !decode_expiry(Some(expires_at)).is_some_and(is_expired) {
+                continue;
+            }
+
+            let was_removed = self.transaction(|values, expiry| {
+                if !decode_expiry(expiry.get(&key)?).is_some_and(is_expired) {
+                    return Ok(false);
+                }
+                values.remove(&key)?;
+                expiry.remove(&key)?;
+                Ok(true)
+            })?;
+
+            if was_removed {
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            self.sync()?;
+        }
+
+        Ok(removed)
+    }
+
+    ///Apply the batch as a pair of sled batches, one for the values and one clearing their expiry times, which sled
+    ///writes together in a single transaction
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut value_batch = sled::Batch::default();
+        let mut expiry_batch = sled::Batch::default();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    expiry_batch.remove(key.as_slice());
+                    value_batch.insert(key, value);
+                }
+                BatchOp::Remove { key } => {
+                    expiry_batch.remove(key.as_slice());
+                    value_batch.remove(key);
+                }
+            }
+        }
+
+        self.transaction(|values, expiry| {
+            values.apply_batch(&value_batch)?;
+            expiry.apply_batch(&expiry_batch)?;
+            Ok(())
+        })?;
+
+        self.sync()
+    }
+
+    ///Compare and swap within a transaction, since a key's value and its expiry time live in separate trees
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<CasOutcome> {
+        let outcome = self.transaction(|values, expiry| {
+            let current = live_value(values, expiry, &key)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(CasOutcome::Mismatch {
+                    current: current.map(|value| value.to_vec()),
+                });
+            }
+
+            match &new {
+                Some(value) => {
+                    values.insert(key.as_slice(), value.as_slice())?;
+                }
+                None => {
+                    values.remove(key.as_slice())?;
+                }
+            }
+            expiry.remove(key.as_slice())?;
+
+            Ok(CasOutcome::Swapped)
+        })?;
+
+        if outcome.is_swapped() {
+            self.sync()?;
+        }
+
+        Ok(outcome)
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> SledScan {
+        SledScan {
+            iter: self.sled_db.range::<Vec<u8>, _>((range.start_bound().cloned(), Bound::Unbounded)),
+            end: range.end_bound().cloned(),
+            expiry: self.expiry.clone(),
+        }
     }
 }
@@ -10,10 +10,17 @@ pub type Result<T> = std::result::Result<T, KvsError>;
 pub enum KvsError {
     Io(io::Error),
     Serde(serde_json::Error),
+    Bincode(bincode::Error),
     Store(String),
     IpAddrParse(AddrParseError),
     CommandError(String),
     SledError(sled::Error),
+    Corruption(String),
+    StoreInUse(String),
+    ThreadPoolError(String),
+    EngineMismatch(String),
+    Metadata(String),
+    KeyNotFound,
 }
 
 impl fmt::Display for KvsError {
@@ -21,14 +28,23 @@ impl fmt::Display for KvsError {
         match self {
             KvsError::Io(err) => write!(f, "IO error: {}", err),
             KvsError::Serde(err) => write!(f, "Serde error: {}", err),
+            KvsError::Bincode(err) => write!(f, "Bincode error: {}", err),
             KvsError::Store(err) => write!(f, "Store error {}", err),
             KvsError::IpAddrParse(err) => write!(f, "IP error {}", err),
             KvsError::CommandError(err) => write!(f, "Command error: {}", err),
             KvsError::SledError(err) => write!(f, "Sled error: {}", err),
+            KvsError::Corruption(err) => write!(f, "Corruption error: {}", err),
+            KvsError::StoreInUse(err) => write!(f, "Store in use: {} is locked by another process", err),
+            KvsError::ThreadPoolError(err) => write!(f, "Thread pool error: {}", err),
+            KvsError::EngineMismatch(err) => write!(f, "Engine mismatch: {}", err),
+            KvsError::Metadata(err) => write!(f, "Metadata error: {}", err),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
         }
     }
 }
 
+impl std::error::Error for KvsError {}
+
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> KvsError {
         KvsError::Io(err)
@@ -41,6 +57,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<net::AddrParseError> for KvsError {
     fn from(err: net::AddrParseError) -> KvsError {
         KvsError::IpAddrParse(err)
@@ -1,11 +1,15 @@
 // #![deny(missing_docs)]
 //!An implementation of a key value store in Rust
-pub use client::KvsClient;
+pub use async_server::AsyncKvsServer;
+pub use client::{AsyncKvsClient, KvsClient};
 pub use server::KvsServer;
 pub use utils::KVS_FILE_NAME;
 
+pub mod async_server;
 pub mod client;
 pub mod engines;
 pub mod error;
+pub mod protocol;
 pub mod server;
+pub mod thread_pool;
 pub mod utils;
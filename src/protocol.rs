@@ -0,0 +1,181 @@
+//!Messages exchanged between KvsClient and KvsServer. Each message is a frame: its length in bytes as a
+//!big-endian u64, followed by the message serialized with bincode
+use crate::engines::WriteBatch;
+use crate::error::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+///A request sent by a client. Keys and values are arbitrary bytes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    Get { key: Vec<u8> },
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+    ///Set the value of a key for a limited time
+    SetWithTtl { key: Vec<u8>, value: Vec<u8>, ttl_ms: u64 },
+    ///Time left before a key expires
+    Ttl { key: Vec<u8> },
+    ///Several writes applied all together or not at all
+    Batch { batch: WriteBatch },
+    ///Set a key to `new`, or remove it if `new` is None, only if its value is `expected`, where None means it has none
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    ///Up to `limit` keys starting with a prefix, in order, with their values. Paging through them, a client
+    ///passes the last key it got as `start_after` to continue from there
+    Scan {
+        prefix: Vec<u8>,
+        start_after: Option<Vec<u8>>,
+        limit: Option<u64>,
+    },
+}
+
+///The server's answer to a single request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    ///The value of the key asked for, if it has one
+    Value(Option<Vec<u8>>),
+    ///Milliseconds left before the key asked about expires, or None if it never does
+    Ttl(Option<u64>),
+    ///The key/value pairs a scan found, in key order
+    Entries(Vec<(Vec<u8>, Vec<u8>)>),
+    ///The request succeeded and there is nothing to send back
+    Ok,
+    ///A compare-and-swap found the key holding another value, or none, and left it as it was
+    Mismatch { current: Option<Vec<u8>> },
+    ///The request failed, with what kind of failure it was and a description of why
+    Err { kind: ErrorKind, message: String },
+}
+
+///What went wrong with a request that failed, so clients can tell a missing key from a broken server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    ///The key does not exist
+    NotFound,
+    ///The request could not be decoded or makes no sense
+    BadRequest,
+    ///The engine failed to carry out the request
+    Engine,
+    ///The server failed to read or write its data
+    Io,
+}
+
+impl Response {
+    ///The error response reporting a failed request
+    pub fn from_error(error: &KvsError) -> Response {
+        let kind = match error {
+            KvsError::KeyNotFound => ErrorKind::NotFound,
+            KvsError::CommandError(_) | KvsError::Bincode(_) => ErrorKind::BadRequest,
+            KvsError::Io(_) => ErrorKind::Io,
+            _ => ErrorKind::Engine,
+        };
+
+        Response::Err {
+            kind,
+            message: error.to_string(),
+        }
+    }
+
+    ///Turn an error response back into the error it reports, passing any other response through
+    pub fn into_result(self) -> Result<Response> {
+        match self {
+            Response::Err { kind, message } => Err(match kind {
+                ErrorKind::NotFound => KvsError::KeyNotFound,
+                ErrorKind::BadRequest => KvsError::CommandError(message),
+                ErrorKind::Engine => KvsError::Store(message),
+                ErrorKind::Io => KvsError::Io(io::Error::other(message)),
+            }),
+            response => Ok(response),
+        }
+    }
+}
+
+///Write a message as a single frame
+pub fn write_frame<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    writer.write_all(&encode_frame(message)?)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+///Read a single frame and decode the message in it
+pub fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> Result<T> {
+    read_next_frame(reader)?.ok_or_else(|| KvsError::Io(io::ErrorKind::UnexpectedEof.into()))
+}
+
+///Read the next frame on a connection carrying several, or None once the other side has closed it between frames
+pub fn read_next_frame<T: DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    let mut length = [0; 8];
+    loop {
+        match reader.read(&mut length[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    reader.read_exact(&mut length[1..])?;
+    let length = u64::from_be_bytes(length);
+
+    //The body grows as it arrives rather than being allocated up front, so a bogus length cannot exhaust memory
+    let mut body = Vec::new();
+    reader.take(length).read_to_end(&mut body)?;
+
+    decode_body(body, length).map(Some)
+}
+
+///Write a message as a single frame without blocking the runtime
+pub async fn write_frame_async<T: Serialize>(writer: &mut (impl AsyncWrite + Unpin), message: &T) -> Result<()> {
+    writer.write_all(&encode_frame(message)?).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+///Read a single frame and decode the message in it without blocking the runtime
+pub async fn read_frame_async<T: DeserializeOwned>(reader: &mut (impl AsyncRead + Unpin)) -> Result<T> {
+    read_next_frame_async(reader)
+        .await?
+        .ok_or_else(|| KvsError::Io(io::ErrorKind::UnexpectedEof.into()))
+}
+
+///Read the next frame on a connection carrying several without blocking the runtime, or None once the other
+///side has closed it between frames
+pub async fn read_next_frame_async<T: DeserializeOwned>(reader: &mut (impl AsyncRead + Unpin)) -> Result<Option<T>> {
+    let mut length = [0; 8];
+    if reader.read(&mut length[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length[1..]).await?;
+    let length = u64::from_be_bytes(length);
+
+    let mut body = Vec::new();
+    reader.take(length).read_to_end(&mut body).await?;
+
+    decode_body(body, length).map(Some)
+}
+
+///   Serialize a message behind its length, so the whole frame goes out in a single write rather than
+///   a small one the next waits behind
+fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    let body = bincode::serialize(message)?;
+
+    let mut frame = Vec::with_capacity(8 + body.len());
+    frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    frame.extend_from_slice(&body);
+
+    Ok(frame)
+}
+
+///   Decode a frame body, which falls short of its length when the connection closed part way through it
+fn decode_body<T: DeserializeOwned>(body: Vec<u8>, length: u64) -> Result<T> {
+    if (body.len() as u64) < length {
+        return Err(KvsError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    Ok(bincode::deserialize(&body)?)
+}
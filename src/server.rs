@@ -1,194 +1,290 @@
-use crate::engines::{KvStore, KvsEngine, SledKvsEngine};
+use crate::engines::{prefix_range, CasOutcome, Durability, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine};
 use crate::error::{KvsError, Result};
-use crate::utils::{
-    BUFFER_LENGTH, GET, KVS_CODE, KVS_FILE_NAME, OK_RESPONSE, RM, SET, SLED_CODE, SLED_FILE_NAME,
+use crate::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, ThreadPoolKind,
 };
-use std::fs;
-use std::io::{Read, Write};
+use crate::protocol::{read_next_frame, write_frame, Request, Response};
+use crate::utils::{DEFAULT_MAX_CONNECTIONS, DEFAULT_SWEEP_INTERVAL_MS, KVS_CODE, SLED_CODE};
 use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use tracing::info;
+use tracing::{error, info, warn};
 use tracing_subscriber;
 pub struct KvsServer {}
 
+///Tunable settings for a KvsServer
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    ///When the engine syncs writes to disc
+    pub durability: Durability,
+    ///Which thread pool performs requests
+    pub pool: ThreadPoolKind,
+    ///Number of threads in the pool, which is also the number of requests performed at once
+    pub threads: u32,
+    ///Most connections kept open at once, each with a thread of its own. Connections beyond it are closed
+    pub max_connections: usize,
+    ///Directory both engines keep their data in, and where the engine already in use is detected
+    pub data_dir: PathBuf,
+    ///How often expired keys are removed in the background
+    pub sweep_interval: Duration,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            durability: Durability::default(),
+            pool: ThreadPoolKind::default(),
+            threads: thread::available_parallelism().map_or(4, |threads| threads.get() as u32),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            data_dir: PathBuf::from("."),
+            sweep_interval: Duration::from_millis(DEFAULT_SWEEP_INTERVAL_MS),
+        }
+    }
+}
+
 impl KvsServer {
-    pub fn route_request(ip_string: String, engine: String) -> Result<()> {
+    pub fn route_request(ip_string: String, engine: String, options: ServerOptions) -> Result<()> {
+        match options.pool {
+            ThreadPoolKind::Naive => KvsServer::route_engine::<NaiveThreadPool>(ip_string, engine, options),
+            ThreadPoolKind::SharedQueue => {
+                KvsServer::route_engine::<SharedQueueThreadPool>(ip_string, engine, options)
+            }
+            ThreadPoolKind::Rayon => KvsServer::route_engine::<RayonThreadPool>(ip_string, engine, options),
+        }
+    }
+
+    fn route_engine<P: ThreadPool + Send + Sync + 'static>(ip_string: String, engine: String, options: ServerOptions) -> Result<()> {
+        let pool = P::new(options.threads)?;
+
         match engine.as_bytes() {
-            KVS_CODE => KvsServer::listen_and_serve_requests_kvs(ip_string, engine),
-            SLED_CODE => KvsServer::listen_and_serve_requests_sled(ip_string, engine),
+            KVS_CODE => KvsServer::listen_and_serve_requests_kvs(ip_string, options, pool),
+            SLED_CODE => KvsServer::listen_and_serve_requests_sled(ip_string, options, pool),
             _ => Err(KvsError::CommandError("Engine not found".to_string())),
         }
     }
 
-    fn listen_and_serve_requests_sled(ip_string: String, engine: String) -> Result<()> {
-        let listener = TcpListener::bind(ip_string)?;
-
-        for stream in listener.incoming() {
-            KvsServer::verify_database_type(engine.clone())?;
+    fn listen_and_serve_requests_sled(
+        ip_string: String,
+        options: ServerOptions,
+        pool: impl ThreadPool + Send + Sync + 'static,
+    ) -> Result<()> {
+        let sled_engine = KvsServer::open_sled(&options)?;
 
-            let sled_db = SledKvsEngine::open(SLED_FILE_NAME)?;
+        KvsServer::spawn_sweeper(sled_engine.clone(), options.sweep_interval);
+        KvsServer::serve(ip_string, sled_engine, pool, options.max_connections)
+    }
 
-            let sled_engine = SledKvsEngine {
-                directory_path: PathBuf::from(SLED_FILE_NAME),
-                sled_db,
-            };
+    fn listen_and_serve_requests_kvs(
+        ip_string: String,
+        options: ServerOptions,
+        pool: impl ThreadPool + Send + Sync + 'static,
+    ) -> Result<()> {
+        let kv_store = KvsServer::open_kvs(&options)?;
 
-            let unwrapped_stream = stream?;
-            KvsServer::handle_request(unwrapped_stream, sled_engine)?
-        }
+        KvsServer::spawn_sweeper(kv_store.clone(), options.sweep_interval);
+        KvsServer::serve(ip_string, kv_store, pool, options.max_connections)
+    }
 
-        Ok(())
+    ///Open the kvs engine in the data directory, which refuses a directory holding sled data
+    pub(crate) fn open_kvs(options: &ServerOptions) -> Result<KvStore> {
+        KvStore::open_with_options(
+            &options.data_dir,
+            KvStoreOptions {
+                durability: options.durability,
+                ..Default::default()
+            },
+        )
     }
 
-    fn listen_and_serve_requests_kvs(ip_string: String, engine: String) -> Result<()> {
-        let path = PathBuf::from("");
+    ///Open the sled engine in the data directory, which refuses a directory holding kvs data
+    pub(crate) fn open_sled(options: &ServerOptions) -> Result<SledKvsEngine> {
+        SledKvsEngine::open(&options.data_dir, options.durability)
+    }
 
+    ///Accept connections and serve each one on a thread of its own, closing any that arrive beyond the connection limit
+    fn serve(
+        ip_string: String,
+        engine: impl KvsEngine,
+        pool: impl ThreadPool + Send + Sync + 'static,
+        max_connections: usize,
+    ) -> Result<()> {
         let listener = TcpListener::bind(ip_string)?;
+        let pool = Arc::new(pool);
+        let open_connections = Arc::new(AtomicUsize::new(0));
 
         for stream in listener.incoming() {
-            KvsServer::verify_database_type(engine.clone())?;
-
-            let kv_store = KvStore::open(&path)?;
-
             let unwrapped_stream = stream?;
+            let _subscriber = tracing_subscriber::FmtSubscriber::new();
 
-            KvsServer::handle_request(unwrapped_stream, kv_store)?
-        }
+            let slot = match ConnectionSlot::acquire(&open_connections, max_connections) {
+                Some(slot) => slot,
+                None => {
+                    warn!("Refusing connection, {} are already open", max_connections);
+                    continue;
+                }
+            };
 
-        Ok(())
-    }
+            info!("Connection initiated");
 
-    fn verify_database_type(engine: String) -> Result<()> {
-        let sled_exists = fs::metadata(SLED_FILE_NAME);
-        let kvs_exists = fs::metadata(KVS_FILE_NAME);
+            unwrapped_stream.set_nodelay(true)?;
 
-        // info!("sled_exists: {:?}", sled_exists);
-        // info!("kvs_exists: {:?}", kvs_exists);
+            let engine = engine.clone();
+            let pool = Arc::clone(&pool);
 
-        if sled_exists.is_ok() && engine.as_bytes() == KVS_CODE {
-            // info!("Cannot use kvs engine when sled db exists");
-            return Err(KvsError::CommandError(
-                "Engine mismatch. Cannot use Kvs Engine for existing Sled Engine".to_string(),
-            ));
-        }
+            thread::spawn(move || {
+                let _slot = slot;
 
-        if kvs_exists.is_ok() && engine.as_bytes() == SLED_CODE {
-            // info!("Cannot use sled engine when kvs db exists");
-            return Err(KvsError::CommandError(
-                "Engine mismatch. Cannot use Sled Engine for existing Kvs Engine".to_string(),
-            ));
+                if let Err(error) = KvsServer::handle_connection(unwrapped_stream, &engine, pool.as_ref()) {
+                    error!("Unable to handle connection: {}", error);
+                }
+            });
         }
 
         Ok(())
     }
 
-    //TODO! Perform operation by calling KvsEngine
-    fn handle_request(mut stream: TcpStream, mut engine: impl KvsEngine) -> Result<()> {
-        let _subscriber = tracing_subscriber::FmtSubscriber::new();
-
-        info!("Connection initiated");
-
-        let mut buffer = [0; BUFFER_LENGTH];
-
-        stream.read(&mut buffer)?;
-
-        //Split arguments by space
-        let arguments: Vec<&[u8]> = buffer.split(|byte| &[*byte] == b"\n").collect();
+    ///Remove expired keys from the engine on a thread of its own every interval, for as long as the server runs
+    fn spawn_sweeper(engine: impl KvsEngine, interval: Duration) {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            KvsServer::sweep(&engine);
+        });
+    }
 
-        match arguments.get(0) {
-            Some(&GET) => {
-                info!("Processing GET Request");
-                //decode key
-                let key_bytes = arguments
-                    .get(1)
-                    .ok_or_else(|| KvsError::CommandError("Command unrecognized".to_string()));
+    ///Remove expired keys from the engine, logging rather than returning a failure since no client is waiting on it
+    pub(crate) fn sweep(engine: &impl KvsEngine) {
+        match engine.remove_expired() {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} expired keys", removed),
+            Err(error) => warn!("Unable to remove expired keys: {}", error),
+        }
+    }
 
-                if let Err(error) = key_bytes {
-                    return Err(error);
-                }
+    ///Answer requests on a connection until the client closes it. The connection's thread blocks while it waits for the
+    ///next request, and each request is performed on the pool
+    fn handle_connection(mut stream: TcpStream, engine: &impl KvsEngine, pool: &impl ThreadPool) -> Result<()> {
+        loop {
+            let response = match read_next_frame::<Request>(&mut stream) {
+                Ok(Some(request)) => KvsServer::respond_on_pool(request, engine, pool)?,
+                Ok(None) => return Ok(()),
+                //The connection failed, so there is no one to answer
+                Err(error @ KvsError::Io(_)) => return Err(error),
+                Err(error) => Response::from_error(&error),
+            };
 
-                let key = String::from_utf8(key_bytes.unwrap().to_vec())?; //NOTE! Is there a better way to handle this?
+            write_frame(&mut stream, &response)?;
+        }
+    }
 
-                //Handle get request (send response back)
-                let result = engine.get(key)?;
+    ///   Perform a request on one of the pool's threads and wait for its response
+    fn respond_on_pool(request: Request, engine: &impl KvsEngine, pool: &impl ThreadPool) -> Result<Response> {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let engine = engine.clone();
 
-                info!("Get result: {:?}", result);
+        pool.spawn(move || {
+            let _result = sender.send(KvsServer::respond(request, &engine));
+        });
 
-                //NOTE! If the result is not Ok(value), then error should propogate to kvs-server and the below should not execute right?
-                //Send result back (encapsulate in function?)
-                let response = format!(
-                    "+{}\n",
-                    result.unwrap_or_else(|| "Key not found".to_string())
-                );
+        receiver
+            .recv()
+            .map_err(|_err| KvsError::ThreadPoolError("The request panicked before it was answered".to_string()))
+    }
 
-                stream.write(response.as_bytes())?;
-                stream.flush()?;
+    ///Perform a request against the engine, turning a failure into an error response for the client
+    pub(crate) fn respond(request: Request, engine: &impl KvsEngine) -> Response {
+        let result = match request {
+            Request::Get { key } => {
+                info!("Processing GET Request");
+                engine.get_bytes(key).map(Response::Value)
             }
-            Some(&SET) => {
+            Request::Set { key, value } => {
                 info!("Processing SET Request");
-                //decode key and value
-                let key_bytes = arguments
-                    .get(1)
-                    .ok_or_else(|| KvsError::CommandError("Command unrecognized".to_string()));
-                let value_bytes = arguments
-                    .get(2)
-                    .ok_or_else(|| KvsError::CommandError("Command unrecognized".to_string()));
-
-                //Handle set request (send success reponse)
-
-                if let Err(error) = key_bytes {
-                    return Err(error);
-                }
-
-                if let Err(error) = value_bytes {
-                    return Err(error);
-                }
-
-                let key = String::from_utf8(key_bytes.unwrap().to_vec())?;
-                let value = String::from_utf8(value_bytes.unwrap().to_vec())?;
-
-                let _result = engine.set(key, value)?;
-
-                //NOTE! If the result is not Ok(value), then error should propogate to kvs-server and the below should not execute right?
-                //Send result back (encapsulate in function?)
-                let response = OK_RESPONSE;
-
-                stream.write(response)?;
-                stream.flush()?;
+                engine.set_bytes(key, value).map(|()| Response::Ok)
             }
-            Some(&RM) => {
+            Request::Remove { key } => {
                 info!("Processing Remove Request");
-                //decode key
-                let key_bytes = arguments
-                    .get(1)
-                    .ok_or_else(|| KvsError::CommandError("Command unrecognized".to_string()));
-                //Handle remove request
-
-                if let Err(error) = key_bytes {
-                    return Err(error);
-                }
-
-                let key = String::from_utf8(key_bytes.unwrap().to_vec())?;
+                engine.remove_bytes(key).map(|()| Response::Ok)
+            }
+            Request::SetWithTtl { key, value, ttl_ms } => {
+                info!("Processing SET Request with a TTL");
+                engine
+                    .set_with_ttl(key, value, Duration::from_millis(ttl_ms))
+                    .map(|()| Response::Ok)
+            }
+            Request::Ttl { key } => {
+                info!("Processing TTL Request");
+                engine
+                    .ttl(key)
+                    .map(|ttl| Response::Ttl(ttl.map(|ttl| u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))))
+            }
+            Request::Batch { batch } => {
+                info!("Processing BATCH Request");
+                engine.apply_batch(batch).map(|()| Response::Ok)
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                info!("Processing CAS Request");
+                engine.compare_and_swap(key, expected, new).map(|outcome| match outcome {
+                    CasOutcome::Swapped => Response::Ok,
+                    CasOutcome::Mismatch { current } => Response::Mismatch { current },
+                })
+            }
+            Request::Scan {
+                prefix,
+                start_after,
+                limit,
+            } => {
+                info!("Processing SCAN Request");
+                KvsServer::scan(engine, prefix, start_after, limit).map(Response::Entries)
+            }
+        };
 
-                let result = engine.remove(key);
+        result.unwrap_or_else(|error| {
+            warn!("Request failed: {}", error);
+            Response::from_error(&error)
+        })
+    }
 
-                //NOTE! If the result is err, then send back Key not found?
-                //Send result back (encapsulate in function?)
+    ///   Collect a page of the keys starting with a prefix, beginning after the last key of the previous page
+    fn scan(
+        engine: &impl KvsEngine,
+        prefix: Vec<u8>,
+        start_after: Option<Vec<u8>>,
+        limit: Option<u64>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (start, end) = prefix_range(prefix);
+        let start = match (start, start_after) {
+            (Bound::Included(prefix), Some(key)) if key >= prefix => Bound::Excluded(key),
+            (start, _) => start,
+        };
+
+        let limit = limit.map_or(usize::MAX, |limit| usize::try_from(limit).unwrap_or(usize::MAX));
+
+        engine.scan((start, end)).take(limit).collect()
+    }
+}
 
-                if let Err(_error) = result {
-                    let result = "Key not found".to_string();
-                    let response = format!("+{}\n", result);
-                    stream.write(response.as_bytes())?;
-                    stream.flush()?;
-                }
-            }
-            _ => {
-                //return error
-                return Err(KvsError::CommandError("Command unrecognized".to_string()));
-            }
-        }
+///   A place among the server's open connections, given back when the connection closes
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    ///   Take a place if fewer than the limit are taken
+    fn acquire(open_connections: &Arc<AtomicUsize>, max_connections: usize) -> Option<ConnectionSlot> {
+        open_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < max_connections).then_some(open + 1)
+            })
+            .ok()
+            .map(|_open| ConnectionSlot(Arc::clone(open_connections)))
+    }
+}
 
-        Ok(())
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
 }
@@ -0,0 +1,74 @@
+use crate::error::{KvsError, Result};
+use std::fmt;
+use std::str::FromStr;
+
+///A pool of threads that runs jobs in the background
+pub trait ThreadPool {
+    ///Create a pool running jobs on the given number of threads. Return an error if there are none
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    ///Run a job on one of the pool's threads. A job that panics does not take the pool down with it
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+///The thread pool implementations a server can be run with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThreadPoolKind {
+    ///A new thread for every job
+    Naive,
+    ///A fixed set of threads taking jobs from a shared queue
+    #[default]
+    SharedQueue,
+    ///A pool backed by rayon
+    Rayon,
+}
+
+impl FromStr for ThreadPoolKind {
+    type Err = KvsError;
+
+    ///Parse `naive`, `shared-queue` or `rayon`
+    fn from_str(s: &str) -> Result<ThreadPoolKind> {
+        match s {
+            "naive" => Ok(ThreadPoolKind::Naive),
+            "shared-queue" => Ok(ThreadPoolKind::SharedQueue),
+            "rayon" => Ok(ThreadPoolKind::Rayon),
+            _ => Err(KvsError::CommandError(format!(
+                "Unknown thread pool {}. Expected naive, shared-queue or rayon",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ThreadPoolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThreadPoolKind::Naive => write!(f, "naive"),
+            ThreadPoolKind::SharedQueue => write!(f, "shared-queue"),
+            ThreadPoolKind::Rayon => write!(f, "rayon"),
+        }
+    }
+}
+
+///Check a pool is asked for at least one thread to run its jobs on
+fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(KvsError::ThreadPoolError(
+            "A thread pool needs at least one thread".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+mod naive;
+mod rayon;
+mod shared_queue;
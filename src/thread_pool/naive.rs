@@ -0,0 +1,22 @@
+use super::{check_threads, ThreadPool};
+use crate::error::Result;
+use std::thread;
+
+///Runs every job on a thread of its own
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    ///The number of threads is ignored, since a thread is started per job
+    fn new(threads: u32) -> Result<NaiveThreadPool> {
+        check_threads(threads)?;
+
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
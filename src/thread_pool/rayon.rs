@@ -0,0 +1,30 @@
+use super::{check_threads, ThreadPool};
+use crate::error::{KvsError, Result};
+
+///Runs jobs on a rayon thread pool
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<RayonThreadPool> {
+        check_threads(threads)?;
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .build()
+            .map_err(|err| KvsError::ThreadPoolError(err.to_string()))?;
+
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        //rayon aborts the process when a spawned job panics, so the panic is caught inside the job
+        self.pool.spawn(move || {
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+        });
+    }
+}
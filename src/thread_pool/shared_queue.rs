@@ -0,0 +1,64 @@
+use super::{check_threads, ThreadPool};
+use crate::error::{KvsError, Result};
+use crossbeam_channel::{Receiver, Sender};
+use std::thread;
+use tracing::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+///Runs jobs on a fixed number of threads that take them from a shared queue.
+///A thread whose job panics is replaced, so the pool keeps its size
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
+        check_threads(threads)?;
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+
+        for _ in 0..threads {
+            spawn_worker(Worker(receiver.clone()))?;
+        }
+
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        //Workers only stop once the pool is dropped, so the queue always has a receiver
+        self.sender
+            .send(Box::new(job))
+            .expect("thread pool has no workers");
+    }
+}
+
+///The receiving end of the queue owned by a worker thread. Dropping it while the thread unwinds starts a replacement
+#[derive(Clone)]
+struct Worker(Receiver<Job>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(err) = spawn_worker(self.clone()) {
+                error!("Unable to replace a panicked thread pool worker: {}", err);
+            }
+        }
+    }
+}
+
+///   Start a thread running jobs from the queue until the pool is dropped
+fn spawn_worker(worker: Worker) -> Result<()> {
+    thread::Builder::new()
+        .spawn(move || {
+            while let Ok(job) = worker.0.recv() {
+                job();
+            }
+        })
+        .map_err(|err| KvsError::ThreadPoolError(err.to_string()))?;
+
+    Ok(())
+}
@@ -1,9 +1,32 @@
-pub const GET: &[u8] = b"GET";
-pub const SET: &[u8] = b"SET";
-pub const RM: &[u8] = b"RM";
-pub const OK_RESPONSE: &[u8] = b"+OK\n";
 pub const KVS_CODE: &[u8] = b"kvs";
 pub const SLED_CODE: &[u8] = b"sled";
 pub const KVS_FILE_NAME: &str = "log.txt";
+pub const LOG_FILE_EXTENSION: &str = "log";
+pub const COMPACTION_FILE_EXTENSION: &str = "compact";
+pub const HINT_FILE_EXTENSION: &str = "hint";
+pub const SEGMENT_MAGIC: &[u8] = b"KVSLOG";
+pub const LOG_FORMAT_VERSION: u8 = 4;
+pub const BINARY_FORMAT_VERSION: u8 = 2;
+pub const BATCH_FORMAT_VERSION: u8 = 3;
+pub const EXPIRY_FORMAT_VERSION: u8 = 4;
+pub const FRAME_HEADER_LENGTH: usize = 8;
+pub const RECORD_HEADER_LENGTH: usize = 13;
+pub const SET_RECORD: u8 = 1;
+pub const RM_RECORD: u8 = 2;
+pub const BATCH_RECORD: u8 = 3;
+pub const SET_WITH_EXPIRY_RECORD: u8 = 4;
+pub const EXPIRY_LENGTH: usize = 8;
+pub const HINT_MAGIC: &[u8] = b"KVSHNT";
+pub const HINT_FORMAT_VERSION: u8 = 2;
+pub const HINT_ENTRY_HEADER_LENGTH: usize = 28;
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 pub const SLED_FILE_NAME: &str = "sled_db";
-pub const BUFFER_LENGTH: usize = 200050;
+pub const SLED_DB_FILE_NAME: &str = "db";
+pub const LOCK_FILE_NAME: &str = "LOCK";
+pub const SLED_RELEASE_TIMEOUT_SECS: u64 = 2;
+pub const META_FILE_NAME: &str = "META";
+pub const SLED_FORMAT_VERSION: u8 = 1;
+pub const SLED_EXPIRY_TREE: &str = "expiry";
+pub const DEFAULT_SWEEP_INTERVAL_MS: u64 = 1000;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
@@ -0,0 +1,98 @@
+use kvs::engines::{AsyncKvsEngine, KvStore};
+use kvs::error::{KvsError, Result};
+use kvs::protocol::{read_frame_async, ErrorKind, Request, Response};
+use kvs::{AsyncKvsClient, AsyncKvsServer};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const IDLE_CONNECTIONS: usize = 2000;
+
+// Engine calls made through the async adapter behave like the blocking ones
+#[tokio::test(flavor = "multi_thread")]
+async fn async_engine_set_get_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::new(KvStore::open(temp_dir.path())?);
+
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, Some("value1".to_owned()));
+
+    engine.remove("key1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    assert!(engine.remove("key1".to_owned()).await.is_err());
+
+    Ok(())
+}
+
+// Requests are still answered while thousands of clients hold connections open without sending anything
+#[tokio::test(flavor = "multi_thread")]
+async fn serve_with_idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::new(KvStore::open(temp_dir.path())?);
+
+    tokio::spawn(AsyncKvsServer::serve(addr.to_owned(), engine));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut idle = Vec::with_capacity(IDLE_CONNECTIONS);
+    for _ in 0..IDLE_CONNECTIONS {
+        idle.push(TcpStream::connect(addr).await?);
+    }
+
+    // Keys and values are framed, so they may hold any bytes and be far larger than a single read
+    let key = b"key\n1\xff".to_vec();
+    let value = b"value\n\x00\xfe".repeat(1024 * 1024);
+
+    let response = AsyncKvsClient::connect_and_send_request(
+        addr.to_owned(),
+        Request::Set {
+            key: key.clone(),
+            value: value.clone(),
+        },
+    )
+    .await?;
+    assert_eq!(response, Response::Ok);
+
+    let response = AsyncKvsClient::connect_and_send_request(addr.to_owned(), Request::Get { key }).await?;
+    assert_eq!(response, Response::Value(Some(value)));
+
+    let response =
+        AsyncKvsClient::connect_and_send_request(addr.to_owned(), Request::Get { key: b"key2".to_vec() })
+            .await?;
+    assert_eq!(response, Response::Value(None));
+
+    // A missing key comes back as its own error, distinct from the server failing
+    match AsyncKvsClient::connect_and_send_request(addr.to_owned(), Request::Remove { key: b"key2".to_vec() })
+        .await
+    {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected a missing key, got {:?}", other),
+    }
+
+    // Requests on a single connection are answered in turn
+    let mut client = AsyncKvsClient::connect(addr.to_owned()).await?;
+    for key_id in 0..100 {
+        client.set(format!("key{}", key_id), format!("value{}", key_id)).await?;
+        assert_eq!(client.get(format!("key{}", key_id)).await?, Some(format!("value{}", key_id)));
+    }
+    client.remove("key1".to_owned()).await?;
+    assert!(matches!(client.remove("key1".to_owned()).await, Err(KvsError::KeyNotFound)));
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+
+    // A frame that does not hold a request is answered with a bad request rather than a dropped connection
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(&3u64.to_be_bytes()).await?;
+    stream.write_all(&[0xff, 0xff, 0xff]).await?;
+    match read_frame_async::<Response>(&mut stream).await? {
+        Response::Err {
+            kind: ErrorKind::BadRequest,
+            ..
+        } => {}
+        other => panic!("expected a bad request, got {:?}", other),
+    }
+
+    drop(idle);
+
+    Ok(())
+}
@@ -20,28 +20,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +52,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +91,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +123,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +134,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,25 +145,247 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_durability() {
+    let temp_dir = TempDir::new().unwrap();
+    for durability in ["sometimes", "every:", "every:0", "every:soon"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--durability", durability, "--addr", "127.0.0.1:4005"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn server_cli_invalid_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    for args in [["--pool", "fifo"], ["--threads", "many"], ["--threads", "0"]] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4005"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+// Servers started from the same working directory keep their data apart in their own data directories
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let servers: Vec<_> = [("kvs", "kvs_data", "127.0.0.1:4011"), ("sled", "sled_data", "127.0.0.1:4012")]
+        .iter()
+        .map(|(engine, data_dir, addr)| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--engine", engine, "--data-dir", data_dir, "--addr", addr])
+                .current_dir(&temp_dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    for (value, addr) in [("value1", "127.0.0.1:4011"), ("value2", "127.0.0.1:4012")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("{}\n", value));
+    }
+
+    for mut server in servers {
+        server.kill().expect("server exited before killed");
+        server.wait().expect("unable to reap server process");
+    }
+
+    assert!(temp_dir.path().join("kvs_data").join("1.log").exists());
+    assert!(temp_dir.path().join("sled_data").join("sled_db").exists());
+    assert!(!temp_dir.path().join("sled_db").exists());
+}
+
+// `kvs-client scan` lists keys in order and pages through them with a limit and the last key listed
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in ["user:2", "user:1", "other", "user:3"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, &format!("value_{}", key), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("other\tvalue_other\nuser:1\tvalue_user:1\nuser:2\tvalue_user:2\nuser:3\tvalue_user:3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "user:", "--limit", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\tvalue_user:1\nuser:2\tvalue_user:2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "user:", "--limit", "2", "--start-after", "user:2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:3\tvalue_user:3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "user:", "--start-after", "user:3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to reap server process");
+}
+
+// `kvs-client cas` only swaps a value that matches the expected one, and reports the current value otherwise
+#[test]
+fn cli_cas() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let cas = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.arg("cas").args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+
+    // Without an expected value the key must not exist yet
+    cas(&["lock", "--new", "owner1"]).assert().success().stdout(is_empty());
+    cas(&["lock", "--new", "owner2"])
+        .assert()
+        .failure()
+        .stderr(contains("current value: owner1"));
+
+    cas(&["lock", "--expected", "owner2", "--new", "owner3"]).assert().failure();
+    cas(&["lock", "--expected", "owner1", "--new", "owner2"]).assert().success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "lock", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("owner2\n");
+
+    // Without a new value the key is removed
+    cas(&["lock", "--expected", "owner2"]).assert().success();
+    cas(&["lock", "--expected", "owner2"])
+        .assert()
+        .failure()
+        .stderr(contains("key not found"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to reap server process");
+}
+
+// `kvs-client set --ttl` sets a key that expires, and `kvs-client ttl` reports the seconds it has left
+#[test]
+fn cli_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4017";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+
+    // Only keys with TTLs far longer than the test are checked before they expire, and the short one only
+    // after sleeping past it, so a slow machine cannot fail the test
+    client(&["set", "session", "token", "--ttl", "3600"]).assert().success().stdout(is_empty());
+    client(&["set", "short", "token", "--ttl", "1"]).assert().success().stdout(is_empty());
+    client(&["set", "key1", "value1"]).assert().success();
+
+    let output = client(&["ttl", "session"]).output().unwrap();
+    assert!(output.status.success());
+    let seconds_left: u64 = String::from_utf8(output.stdout).unwrap().trim().parse().unwrap();
+    assert!(seconds_left > 3000 && seconds_left <= 3600);
+    client(&["ttl", "key1"]).assert().success().stdout("No expiry\n");
+    client(&["ttl", "missing"]).assert().success().stdout("Key not found\n");
+    client(&["get", "session"]).assert().success().stdout("token\n");
+
+    thread::sleep(Duration::from_millis(1500));
+
+    client(&["get", "short"]).assert().success().stdout("Key not found\n");
+    client(&["ttl", "short"]).assert().success().stdout("Key not found\n");
+    client(&["get", "session"]).assert().success().stdout("token\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to reap server process");
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("unable to reap server process");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,19 +400,20 @@ fn cli_wrong_engine() {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server process");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4002"])
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4002"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -198,12 +421,12 @@ fn cli_wrong_engine() {
 
     Command::cargo_bin("kvs-server")
         .unwrap()
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
         .current_dir(&temp_dir);
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4003"])
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4003"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -214,7 +437,7 @@ fn cli_wrong_engine() {
     //     let temp_dir = TempDir::new().unwrap();
     //     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     //     let mut child = cmd
-    //         .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+    //         .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
     //         .current_dir(&temp_dir)
     //         .spawn()
     //         .unwrap();
@@ -222,7 +445,7 @@ fn cli_wrong_engine() {
     //     child.kill().expect("server exited before killed");
 
     //     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    //     cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+    //     cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
     //         .current_dir(&temp_dir)
     //         .assert()
     //         .failure();
@@ -233,19 +456,20 @@ fn cli_wrong_engine() {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server process");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4002"])
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4002"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,12 +477,12 @@ fn cli_wrong_engine() {
 
     Command::cargo_bin("kvs-server")
         .unwrap()
-        .args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
         .current_dir(&temp_dir);
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4003"])
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4003"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -267,24 +491,26 @@ fn cli_wrong_engine() {
     handle.join().unwrap();
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+fn cli_access_server(engine: &str, server_args: &[&str], addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
+        .args(server_args)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server process");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -292,7 +518,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -300,7 +526,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -308,7 +534,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -316,7 +542,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -324,7 +550,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -332,7 +558,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -340,7 +566,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -353,26 +579,28 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
+        .args(server_args)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server process");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -383,10 +611,30 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", &["--pool", "shared-queue"], "127.0.0.1:4004");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", &["--pool", "shared-queue"], "127.0.0.1:4005");
+}
+
+#[test]
+fn cli_access_server_naive_pool() {
+    cli_access_server("kvs", &["--pool", "naive"], "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_access_server_rayon_pool() {
+    cli_access_server("kvs", &["--pool", "rayon"], "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_access_server_async() {
+    cli_access_server("kvs", &["--async"], "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_access_server_async_sled_engine() {
+    cli_access_server("sled", &["--async"], "127.0.0.1:4009");
 }
@@ -0,0 +1,361 @@
+use kvs::engines::{KvStore, KvStoreOptions, KvsEngine, WriteBatch};
+use kvs::error::{KvsError, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+// A segment file's path and contents
+type Segment = (PathBuf, Vec<u8>);
+
+// Small segments so the data spans several of them, and no automatic compaction
+fn options() -> KvStoreOptions {
+    KvStoreOptions {
+        segment_size: 512,
+        compaction_threshold: u64::MAX,
+        ..Default::default()
+    }
+}
+
+// Fill a store with overwritten and removed keys, with every removal in a later segment than its key's value
+fn populate(path: &Path) -> Result<()> {
+    let store = KvStore::open_with_options(path, options())?;
+
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value1".to_owned())?;
+    }
+    for key_id in 0..25 {
+        store.set(format!("key{}", key_id), "value2".to_owned())?;
+    }
+    for key_id in 25..35 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    Ok(())
+}
+
+fn check_contents(path: &Path) -> Result<()> {
+    let store = KvStore::open_with_options(path, options())?;
+
+    for key_id in 0..25 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value2".to_owned()));
+    }
+    for key_id in 25..35 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 35..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Segment files in a directory, oldest first, with their contents
+fn segments(path: &Path) -> Vec<Segment> {
+    let mut segments: Vec<(u64, PathBuf)> = fs::read_dir(path)
+        .expect("unable to read directory")
+        .map(|entry| entry.expect("unable to read directory entry").path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| {
+            let gen = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            (gen, path)
+        })
+        .collect();
+    segments.sort();
+
+    segments
+        .into_iter()
+        .map(|(_, path)| {
+            let contents = fs::read(&path).expect("unable to read segment");
+            (path, contents)
+        })
+        .collect()
+}
+
+// Compact the store, returning the sealed segments it replaced and the compacted segment
+fn compact(path: &Path) -> Result<(Vec<Segment>, Segment)> {
+    let sealed = segments(path);
+
+    let store = KvStore::open_with_options(path, options())?;
+    store.compact()?;
+    drop(store);
+
+    let compacted = segments(path)
+        .into_iter()
+        .find(|(path, _)| !sealed.iter().any(|(sealed_path, _)| sealed_path == path))
+        .expect("no compacted segment written");
+
+    for (path, _) in sealed.iter() {
+        assert!(!path.exists());
+    }
+
+    Ok((sealed, compacted))
+}
+
+// Interrupted while writing the compacted segment: only a partial temporary file exists
+#[test]
+fn crash_while_writing_compacted_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let (sealed, compacted) = compact(temp_dir.path())?;
+
+    fs::remove_file(&compacted.0)?;
+    for (path, contents) in sealed.iter() {
+        fs::write(path, contents)?;
+    }
+    let temp_path = compacted.0.with_extension("compact");
+    fs::write(&temp_path, &compacted.1[..compacted.1.len() / 2])?;
+
+    check_contents(temp_dir.path())?;
+    assert!(!temp_path.exists());
+
+    Ok(())
+}
+
+// Interrupted after the compacted segment was synced but before it was renamed into place
+#[test]
+fn crash_before_rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let (sealed, compacted) = compact(temp_dir.path())?;
+
+    let temp_path = compacted.0.with_extension("compact");
+    fs::rename(&compacted.0, &temp_path)?;
+    for (path, contents) in sealed.iter() {
+        fs::write(path, contents)?;
+    }
+
+    check_contents(temp_dir.path())?;
+    assert!(!temp_path.exists());
+
+    Ok(())
+}
+
+// Interrupted after the rename, before any sealed segment was removed
+#[test]
+fn crash_before_removing_sealed_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let (sealed, _) = compact(temp_dir.path())?;
+
+    for (path, contents) in sealed.iter() {
+        fs::write(path, contents)?;
+    }
+
+    check_contents(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Interrupted part way through removing the sealed segments, oldest first
+#[test]
+fn crash_while_removing_sealed_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let (sealed, _) = compact(temp_dir.path())?;
+    assert!(sealed.len() > 2);
+
+    for removed in 1..sealed.len() {
+        for (path, contents) in sealed[removed..].iter() {
+            fs::write(path, contents)?;
+        }
+
+        check_contents(temp_dir.path())?;
+
+        for (path, _) in sealed[removed..].iter() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// A write cut short by a crash is truncated away on open and the store stays writable
+#[test]
+fn torn_write_is_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let (path, contents) = segments(temp_dir.path()).pop().expect("no segments written");
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("torn".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // Keep only part of the last frame
+    let torn_contents = fs::read(&path)?;
+    fs::write(&path, &torn_contents[..torn_contents.len() - 3])?;
+
+    check_contents(temp_dir.path())?;
+    assert_eq!(fs::read(&path)?, contents);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("torn".to_owned())?, None);
+    store.set("torn".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("torn".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A batch cut short by a crash is dropped whole on open, leaving none of its writes behind
+#[test]
+fn torn_batch_is_dropped_whole() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"batch1".to_vec(), b"value".to_vec());
+    batch.set(b"key0".to_vec(), b"value3".to_vec());
+    batch.remove(b"key40".to_vec());
+    batch.set(b"batch2".to_vec(), b"value".to_vec());
+    store.apply_batch(batch)?;
+    drop(store);
+
+    // Keep everything but the end of the batch, so its first writes are intact on disc, then cut it right after
+    // its first write, leaving nothing but whole records after the batch's own header
+    let (path, contents) = segments(temp_dir.path()).pop().expect("no segments written");
+    let first_key_offset = contents
+        .windows(b"batch1".len())
+        .position(|window| window == b"batch1")
+        .expect("batch not found in segment");
+    let first_write_end = first_key_offset + b"batch1".len() + b"value".len();
+
+    for torn_len in [contents.len() - 3, first_write_end] {
+        fs::write(&path, &contents[..torn_len])?;
+
+        check_contents(temp_dir.path())?;
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        assert_eq!(store.get("batch1".to_owned())?, None);
+        assert_eq!(store.get("batch2".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// A record whose length was damaged so that it claims to run past the end of its segment is reported rather than
+// being truncated away as a torn write, in the newest segment as well as in sealed ones
+#[test]
+fn damaged_length_is_reported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let segments = segments(temp_dir.path());
+    for (path, contents) in [segments.first(), segments.last()].into_iter().flatten() {
+        // The high byte of the key length of the first record, which follows the segment's magic and version
+        let mut damaged = contents.clone();
+        damaged[b"KVSLOG".len() + 1 + 8] ^= 0x80;
+        fs::write(path, &damaged)?;
+
+        match KvStore::open_with_options(temp_dir.path(), options()) {
+            Err(KvsError::Corruption(_)) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("damaged length not detected"),
+        }
+        assert_eq!(fs::read(path)?, damaged);
+
+        fs::write(path, contents)?;
+    }
+
+    check_contents(temp_dir.path())?;
+
+    Ok(())
+}
+
+// A record cut short at the end of a sealed segment is reported, since only the newest segment is written to
+#[test]
+fn torn_sealed_segment_is_reported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let (path, contents) = segments(temp_dir.path()).remove(0);
+    fs::write(&path, &contents[..contents.len() - 3])?;
+
+    match KvStore::open_with_options(temp_dir.path(), options()) {
+        Err(KvsError::Corruption(_)) => Ok(()),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("torn sealed segment not detected"),
+    }
+}
+
+// A damaged frame followed by intact ones is reported instead of being skipped
+#[test]
+fn corruption_is_reported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let (path, mut contents) = segments(temp_dir.path()).remove(0);
+    let value_offset = contents
+        .windows(b"value1".len())
+        .position(|window| window == b"value1")
+        .expect("value not found in segment");
+    contents[value_offset] = b'V';
+    fs::write(&path, &contents)?;
+
+    match KvStore::open_with_options(temp_dir.path(), options()) {
+        Err(KvsError::Corruption(_)) => Ok(()),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("corruption not detected"),
+    }
+}
+
+// Compaction writes a hint file, which open reads instead of the compacted segment's values
+#[test]
+fn hint_file_is_used_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let (_, (compacted_path, mut contents)) = compact(temp_dir.path())?;
+
+    let hint_path = compacted_path.with_extension("hint");
+    assert!(hint_path.exists());
+    check_contents(temp_dir.path())?;
+
+    // A damaged value goes unnoticed while the hint is used, and is caught once the segment has to be replayed
+    let value_offset = contents
+        .windows(b"value1".len())
+        .position(|window| window == b"value1")
+        .expect("value not found in segment");
+    contents[value_offset] = b'V';
+    fs::write(&compacted_path, &contents)?;
+    KvStore::open_with_options(temp_dir.path(), options())?;
+
+    fs::remove_file(&hint_path)?;
+    match KvStore::open_with_options(temp_dir.path(), options()) {
+        Err(KvsError::Corruption(_)) => Ok(()),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("segment not replayed without its hint"),
+    }
+}
+
+// A hint that is damaged or does not match its segment is discarded and the segment replayed instead
+#[test]
+fn invalid_hint_file_falls_back_to_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let (_, (compacted_path, _)) = compact(temp_dir.path())?;
+
+    let hint_path = compacted_path.with_extension("hint");
+    let hint = fs::read(&hint_path)?;
+
+    // Damaged entry
+    let mut damaged = hint.clone();
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 0xff;
+    fs::write(&hint_path, &damaged)?;
+    check_contents(temp_dir.path())?;
+    assert!(!hint_path.exists());
+
+    // Cut short
+    fs::write(&hint_path, &hint[..hint.len() - 10])?;
+    check_contents(temp_dir.path())?;
+    assert!(!hint_path.exists());
+
+    // Left behind without its segment
+    fs::write(compacted_path.with_file_name("1000.hint"), &hint)?;
+    check_contents(temp_dir.path())?;
+    assert!(!compacted_path.with_file_name("1000.hint").exists());
+
+    Ok(())
+}
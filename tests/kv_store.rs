@@ -1,5 +1,12 @@
-use kvs::engines::{KvStore, KvsEngine};
-use kvs::error::Result;
+use kvs::engines::{CasOutcome, Durability, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, WriteBatch};
+use kvs::error::{KvsError, Result};
+use kvs::utils::{
+    BATCH_FORMAT_VERSION, BATCH_RECORD, BINARY_FORMAT_VERSION, LOG_FORMAT_VERSION, RM_RECORD, SET_RECORD,
+    SET_WITH_EXPIRY_RECORD,
+};
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -7,7 +14,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -17,7 +24,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -28,7 +35,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -37,7 +44,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -49,14 +56,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -65,21 +72,381 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+// Keys and values that are not valid UTF-8 are stored as they are, through compaction and across reopening
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = vec![0xff, 0x00, b'\n', 0xfe];
+    let value = vec![0xc3, 0x28, 0x00, 0xa0, 0xa1];
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(b"key2".to_vec(), Vec::new())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+    assert!(store.get_bytes(b"key".to_vec())?.is_none());
+    assert!(store.get(String::from_utf8_lossy(&key).into_owned())?.is_none());
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value));
+    assert_eq!(store.get_bytes(b"key2".to_vec())?, Some(Vec::new()));
+
+    Ok(())
+}
+
+// Runs each check written against KvsEngine as its own test on a fresh KvStore and on a fresh SledKvsEngine
+macro_rules! engine_matrix {
+    ($($check:ident),* $(,)?) => {
+        mod kvs_engine {
+            use super::*;
+            $(
+                #[test]
+                fn $check() -> Result<()> {
+                    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+                    super::$check(&KvStore::open(temp_dir.path())?)
+                }
+            )*
+        }
+
+        mod sled_engine {
+            use super::*;
+            $(
+                #[test]
+                fn $check() -> Result<()> {
+                    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+                    super::$check(&SledKvsEngine::open(temp_dir.path(), Durability::default())?)
+                }
+            )*
+        }
+    };
+}
+
+engine_matrix!(binary_values, scan_ranges_and_prefixes, write_batches, compare_and_swap, ttl_expiry);
+
+// Keys and values that are not valid UTF-8 can be written, read and removed through the byte methods
+fn binary_values(engine: &impl KvsEngine) -> Result<()> {
+    let key = vec![0xff, 0x00, b'\n', 0xfe];
+    let value = vec![0xc3, 0x28, 0x00, 0xa0, 0xa1];
+
+    engine.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+
+    // The string convenience methods refuse values that are not UTF-8 rather than mangling them
+    engine.set_bytes(b"key2".to_vec(), value)?;
+    assert!(engine.get("key2".to_owned()).is_err());
+
+    engine.remove_bytes(key.clone())?;
+    assert!(engine.get_bytes(key)?.is_none());
+
+    Ok(())
+}
+
+// Scans return the keys in a range or under a prefix in order
+fn scan_ranges_and_prefixes(engine: &impl KvsEngine) -> Result<()> {
+    for key in ["bb", "a", "ba", "c", "b"] {
+        engine.set(key.to_owned(), format!("value_{}", key))?;
+    }
+    engine.set_bytes(vec![0xff, 0xff], b"high".to_vec())?;
+    engine.set_bytes(vec![0xff, 0xff, 0x00], b"higher".to_vec())?;
+    engine.remove("c".to_owned())?;
+
+    let entries = engine.scan(..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 6);
+    assert_eq!(entries[0], (b"a".to_vec(), b"value_a".to_vec()));
+    assert_eq!(entries[3], (b"bb".to_vec(), b"value_bb".to_vec()));
+
+    assert_eq!(keys(engine.scan(b"b".to_vec()..b"bb".to_vec()))?, [b"b".to_vec(), b"ba".to_vec()]);
+    assert_eq!(keys(engine.scan(b"b".to_vec()..=b"bb".to_vec()))?.len(), 3);
+    assert_eq!(keys(engine.scan_prefix(b"b".to_vec()))?, [b"b".to_vec(), b"ba".to_vec(), b"bb".to_vec()]);
+    assert_eq!(keys(engine.scan_prefix(vec![0xff]))?.len(), 2);
+    assert!(keys(engine.scan_prefix(b"c".to_vec()))?.is_empty());
+
+    // A range that ends before it starts is empty rather than an error
+    assert!(keys(engine.scan(b"bb".to_vec()..b"a".to_vec()))?.is_empty());
+
+    Ok(())
+}
+
+fn keys(scan: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>) -> Result<Vec<Vec<u8>>> {
+    scan.map(|entry| entry.map(|(key, _)| key)).collect()
+}
+
+// A scan looks each key up as it advances, and reads the index, so it sees the same keys once the index is
+// rebuilt from the log
+#[test]
+fn scan_while_writing_and_after_reopening() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["a", "b", "ba", "bb"] {
+        store.set(key.to_owned(), format!("value_{}", key))?;
+    }
+
+    // Keys written ahead of the scan while it runs are found
+    let mut scan = store.scan_prefix(b"b".to_vec());
+    assert_eq!(scan.next().transpose()?.map(|(key, _)| key), Some(b"b".to_vec()));
+    store.set("bc".to_owned(), "value_bc".to_owned())?;
+    assert_eq!(keys(scan)?, [b"ba".to_vec(), b"bb".to_vec(), b"bc".to_vec()]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(keys(store.scan_prefix(b"b".to_vec()))?.len(), 4);
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(keys(store.scan_prefix(b"b".to_vec()))?.len(), 4);
+    assert_eq!(keys(store.scan(..))?.len(), 5);
+
+    Ok(())
+}
+
+// A batch applies all of its writes in order
+fn write_batches(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.apply_batch(WriteBatch::new())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.remove(b"key1".to_vec());
+    // Later writes to a key in the same batch win, and removing a missing key is not an error
+    batch.set(b"key2".to_vec(), b"value2_again".to_vec());
+    batch.remove(b"missing".to_vec());
+    assert_eq!(batch.len(), 5);
+    engine.apply_batch(batch)?;
+
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2_again".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    let mut batch = WriteBatch::new();
+    batch.remove(b"key3".to_vec());
+    batch.set(b"key4".to_vec(), b"value4".to_vec());
+    engine.apply_batch(batch)?;
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert_eq!(engine.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Batches survive reopening and compaction like single writes
+#[test]
+fn batches_survive_reopen_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key1".to_vec());
+    batch.set(b"key2".to_vec(), b"value2_again".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    store.apply_batch(batch)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2_again".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2_again".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Compare-and-swap and the conditional writes built on it only write when the key holds the expected value
+fn compare_and_swap(engine: &impl KvsEngine) -> Result<()> {
+    let key = b"key1".to_vec();
+
+    assert!(engine.set_if_absent(key.clone(), b"value1".to_vec())?);
+    assert!(!engine.set_if_absent(key.clone(), b"value2".to_vec())?);
+
+    assert_eq!(
+        engine.compare_and_swap(key.clone(), Some(b"value2".to_vec()), Some(b"value3".to_vec()))?,
+        CasOutcome::Mismatch {
+            current: Some(b"value1".to_vec())
+        }
+    );
+    assert_eq!(
+        engine.compare_and_swap(key.clone(), Some(b"value1".to_vec()), Some(b"value3".to_vec()))?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(engine.get_bytes(key.clone())?, Some(b"value3".to_vec()));
+
+    assert!(!engine.remove_if_equals(key.clone(), b"value1".to_vec())?);
+    assert!(engine.remove_if_equals(key.clone(), b"value3".to_vec())?);
+    assert_eq!(engine.get_bytes(key.clone())?, None);
+    assert_eq!(
+        engine.compare_and_swap(key.clone(), Some(b"value3".to_vec()), None)?,
+        CasOutcome::Mismatch { current: None }
+    );
+    assert_eq!(engine.compare_and_swap(key, None, None)?, CasOutcome::Swapped);
+
+    Ok(())
+}
+
+// Clones of a store incrementing a counter with compare-and-swap from many threads should lose no increment
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    loop {
+                        let current = store.get_bytes(b"counter".to_vec())?;
+                        let count: u64 = String::from_utf8(current.clone().unwrap())?.parse().unwrap();
+                        let new = (count + 1).to_string().into_bytes();
+                        if store.compare_and_swap(b"counter".to_vec(), current, Some(new))?.is_swapped() {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// Keys set with a TTL read as missing once it has passed, and are removed for good by remove_expired
+fn ttl_expiry(engine: &impl KvsEngine) -> Result<()> {
+    // Keys that should expire get a TTL far shorter than the sleep below and are only checked after it, and
+    // keys checked before then get one far longer than any test run, so no assertion depends on timing
+    engine.set_with_ttl(b"short".to_vec(), b"value1".to_vec(), Duration::from_millis(1))?;
+    engine.set_with_ttl(b"long".to_vec(), b"value2".to_vec(), Duration::from_secs(3600))?;
+    engine.set_with_ttl(b"cleared".to_vec(), b"value3".to_vec(), Duration::from_millis(1))?;
+    engine.set("cleared".to_owned(), "value4".to_owned())?;
+    engine.set("plain".to_owned(), "value5".to_owned())?;
+
+    assert_eq!(engine.get("long".to_owned())?, Some("value2".to_owned()));
+    let ttl = engine.ttl(b"long".to_vec())?.expect("no expiry");
+    assert!(ttl > Duration::from_secs(3000) && ttl <= Duration::from_secs(3600));
+    assert_eq!(engine.ttl(b"plain".to_vec())?, None);
+    assert!(matches!(engine.ttl(b"missing".to_vec()), Err(KvsError::KeyNotFound)));
+
+    thread::sleep(Duration::from_millis(50));
+
+    // Expired keys read as missing before they are removed
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert!(matches!(engine.ttl(b"short".to_vec()), Err(KvsError::KeyNotFound)));
+    assert!(matches!(engine.remove("short".to_owned()), Err(KvsError::KeyNotFound)));
+    assert_eq!(engine.get("cleared".to_owned())?, Some("value4".to_owned()));
+    let keys = engine
+        .scan(..)
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, [b"cleared".to_vec(), b"long".to_vec(), b"plain".to_vec()]);
+
+    assert_eq!(engine.remove_expired()?, 1);
+    assert_eq!(engine.remove_expired()?, 0);
+
+    // An expired key can be set again
+    assert!(engine.set_if_absent(b"short".to_vec(), b"value6".to_vec())?);
+    assert_eq!(engine.get("short".to_owned())?, Some("value6".to_owned()));
+
+    Ok(())
+}
+
+// Expiry times are kept in the log: reopening skips expired values, and compaction drops them and carries
+// the expiry times of the rest into its hint file
+#[test]
+fn ttl_survives_reopen_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+    store.set_with_ttl(b"key1".to_vec(), b"expiring".to_vec(), Duration::from_millis(1))?;
+    store.set_with_ttl(b"key2".to_vec(), b"lasting".to_vec(), Duration::from_secs(3600))?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(50));
+
+    // The expired value hides the older one it replaced rather than bringing it back
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.ttl(b"key2".to_vec())?.is_some());
+
+    store.set_with_ttl(b"key3".to_vec(), b"expiring".to_vec(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(50));
+    store.compact()?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+
+    let compacted = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .map(|entry| fs::read(entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?
+        .concat();
+    assert!(!compacted.windows(b"expiring".len()).any(|window| window == b"expiring"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("lasting".to_owned()));
+    assert!(store.ttl(b"key2".to_vec())?.is_some());
+
+    Ok(())
+}
+
+// remove_expired only sweeps keys whose expiry has passed, and compaction takes the expired keys it drops
+// out of the index so they are not swept again or counted as stale afterwards
+#[test]
+fn expired_keys_leave_the_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["key1", "key2", "key3"] {
+        store.set_with_ttl(key.as_bytes().to_vec(), b"expiring".to_vec(), Duration::from_millis(1))?;
+    }
+    store.set_with_ttl(b"key4".to_vec(), b"lasting".to_vec(), Duration::from_secs(3600))?;
+    thread::sleep(Duration::from_millis(50));
+
+    let stale_bytes = store.stale_bytes()?;
+    assert_eq!(store.remove_expired()?, 3);
+    assert!(store.stale_bytes()? > stale_bytes);
+    assert_eq!(store.get("key4".to_owned())?, Some("lasting".to_owned()));
+
+    store.set_with_ttl(b"key5".to_vec(), b"expiring".to_vec(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(50));
+    store.compact()?;
+    assert_eq!(store.stale_bytes()?, 0);
+    assert_eq!(store.remove_expired()?, 0);
+    assert_eq!(store.stale_bytes()?, 0);
+    assert_eq!(store.get("key4".to_owned())?, Some("lasting".to_owned()));
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -107,7 +474,8 @@ fn compaction() -> Result<()> {
         }
 
         // reopen and check content.
-        let store = KvStore::open(temp_dir.path())?;
+        drop(store);
+        store = KvStore::open(temp_dir.path())?;
 
         let new_size = dir_size();
         if new_size > current_size {
@@ -118,7 +486,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -128,3 +496,546 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Writes past the segment size should roll over into new numbered segment files
+#[test]
+fn segments_roll_over() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let segment_count = || {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read directory")
+            .filter(|entry| {
+                entry.as_ref().unwrap().path().extension() == Some("log".as_ref())
+            })
+            .count()
+    };
+    assert!(segment_count() > 1);
+
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// A single log.txt written by older versions should be picked up as the first segment
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log.txt"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":{"key":"key2"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(!temp_dir.path().join("log.txt").exists());
+
+    Ok(())
+}
+
+// Overwrites past the stale byte threshold should be compacted while the store stays open
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 4096,
+        compaction_threshold: 16 * 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    // The background compaction removes segments while the directory is walked, so files that vanish are skipped
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|res| res.and_then(|entry| entry.metadata()).ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
+    };
+
+    let mut max_size = 0;
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("{}", iter)));
+        }
+        max_size = max_size.max(dir_size());
+    }
+
+    // 200 rounds of overwrites take far more space than the threshold allows to pile up
+    assert!(max_size < 200 * 100 * 30 / 4);
+
+    store.compact()?;
+    assert_eq!(store.stale_bytes()?, 0);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A background compaction is swapped in as soon as it is written, without waiting for another write
+#[test]
+fn compaction_installs_without_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 4096,
+        compaction_threshold: 16 * 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let mut iter = 0;
+    while store.stale_bytes()? <= options.compaction_threshold {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+
+    let started = Instant::now();
+    while store.stale_bytes()? > options.compaction_threshold {
+        assert!(started.elapsed() < Duration::from_secs(5), "compaction was not installed");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("{}", iter - 1)));
+    }
+
+    Ok(())
+}
+
+// Segments in the older JSON formats are readable and get rewritten in the binary format
+#[test]
+fn migrate_json_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // Bare JSON commands, as written before segments had a header
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+
+    // Format version 1: length-prefixed, checksummed JSON frames
+    let mut segment = b"KVSLOG\x01".to_vec();
+    for command in [
+        r#"{"Set":{"key":"key3","value":"value3"}}"#,
+        r#"{"Rm":{"key":"key2"}}"#,
+    ] {
+        segment.extend_from_slice(&(command.len() as u32).to_le_bytes());
+        segment.extend_from_slice(&crc32fast::hash(command.as_bytes()).to_le_bytes());
+        segment.extend_from_slice(command.as_bytes());
+    }
+    fs::write(temp_dir.path().join("2.log"), segment)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            assert!(fs::read(path)?.starts_with(&segment_header(LOG_FORMAT_VERSION)));
+        }
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Writes should be readable and survive a reopen under every durability policy
+#[test]
+fn durability_policies() -> Result<()> {
+    for durability in [
+        Durability::Always,
+        Durability::EveryMs(5),
+        Durability::GroupCommit,
+        Durability::OsBuffered,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            segment_size: 1024,
+            durability,
+            ..Default::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        for key_id in 0..50 {
+            store.remove(format!("key{}", key_id))?;
+        }
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..50 {
+            assert_eq!(store.get(format!("key{}", key_id))?, None);
+        }
+        for key_id in 50..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        }
+    }
+
+    Ok(())
+}
+
+// Writers committing at the same time under group commit should share syncs instead of each syncing on its own
+#[test]
+fn group_commit_shares_syncs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        durability: Durability::GroupCommit,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..50 {
+                    store.set(format!("key{}_{}", thread_id, key_id), format!("value{}", key_id))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let syncs = store.syncs()?;
+    assert!(syncs > 0);
+    assert!(syncs < 400, "{} syncs for 400 writes", syncs);
+
+    for thread_id in 0..8 {
+        for key_id in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// The header a segment in the given format version starts with
+fn segment_header(version: u8) -> Vec<u8> {
+    let mut header = b"KVSLOG".to_vec();
+    header.push(version);
+    header
+}
+
+// Lay out a binary record: a checksum of the rest, the op, the key and value lengths, then the key and value
+fn binary_record(op: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut fields = vec![op];
+    fields.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    fields.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    fields.extend_from_slice(key);
+    fields.extend_from_slice(value);
+
+    let mut record = crc32fast::hash(&fields).to_le_bytes().to_vec();
+    record.extend_from_slice(&fields);
+    record
+}
+
+// Segments in older binary format versions are readable and get rewritten in the current one
+#[test]
+fn migrate_older_binary_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut segment = segment_header(BINARY_FORMAT_VERSION);
+    segment.extend_from_slice(&binary_record(SET_RECORD, b"key1", b"value1"));
+    segment.extend_from_slice(&binary_record(SET_RECORD, b"key2", b"value2"));
+    segment.extend_from_slice(&binary_record(RM_RECORD, b"key2", b""));
+    fs::write(temp_dir.path().join("1.log"), segment)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            assert!(fs::read(path)?.starts_with(&segment_header(LOG_FORMAT_VERSION)));
+        }
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A whole record with an op its segment's format version does not have fails to open rather than being truncated
+// away as a torn write
+#[test]
+fn unknown_record_ops_are_rejected() -> Result<()> {
+    for (version, op) in [
+        (BINARY_FORMAT_VERSION, BATCH_RECORD),
+        (BATCH_FORMAT_VERSION, SET_WITH_EXPIRY_RECORD),
+        (LOG_FORMAT_VERSION, u8::MAX),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let path = temp_dir.path().join("1.log");
+
+        let mut segment = segment_header(version);
+        segment.extend_from_slice(&binary_record(SET_RECORD, b"key1", b"value1"));
+        segment.extend_from_slice(&binary_record(op, b"", &binary_record(SET_RECORD, b"key2", b"value2")));
+        fs::write(&path, &segment)?;
+
+        assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Store(_))));
+        assert_eq!(fs::read(&path)?, segment);
+    }
+
+    Ok(())
+}
+
+// Durability policies should parse from and display as the strings the server flag accepts
+#[test]
+fn parse_durability() {
+    for (text, durability) in [
+        ("always", Durability::Always),
+        ("every:100", Durability::EveryMs(100)),
+        ("group-commit", Durability::GroupCommit),
+        ("os-buffered", Durability::OsBuffered),
+    ] {
+        assert_eq!(text.parse::<Durability>().unwrap(), durability);
+        assert_eq!(durability.to_string(), text);
+    }
+
+    for text in ["sometimes", "every:", "every:0", "every:soon"] {
+        assert!(text.parse::<Durability>().is_err());
+    }
+}
+
+// A store that is already open elsewhere cannot be opened again until it is dropped
+#[test]
+fn open_store_in_use() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreInUse(_)) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("store opened twice"),
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A sled database that is already open elsewhere cannot be opened again until it is dropped
+#[test]
+fn open_sled_engine_in_use() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("sled_db");
+    let path = path.to_str().expect("temporary path is not valid UTF-8");
+    let engine = SledKvsEngine::open(path, Durability::default())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    match SledKvsEngine::open(path, Durability::default()) {
+        Err(KvsError::StoreInUse(_)) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("database opened twice"),
+    }
+
+    drop(engine);
+    let engine = SledKvsEngine::open(path, Durability::default())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Reopening straight after each drop succeeds even while sled's own threads are still letting go of its database
+    drop(engine);
+    for round in 0..20 {
+        let engine = SledKvsEngine::open(path, Durability::default())?;
+        engine.set("round".to_owned(), round.to_string())?;
+    }
+
+    Ok(())
+}
+
+// Clones of a store written to from many threads at once should all see every write
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 4096,
+        compaction_threshold: 16 * 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..50 {
+                    for key_id in (thread_id * 100)..(thread_id * 100 + 100) {
+                        store.set(format!("key{}", key_id), format!("value{}", iter))?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("writer thread panicked")?;
+    }
+
+    for key_id in 0..800 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value49".to_owned()));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..800 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value49".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Clones of a store read from many threads while another thread keeps overwriting should always see a value
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 4096,
+        compaction_threshold: 16 * 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 1..100 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), format!("{}", iter))?;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..20 {
+                    for key_id in 0..100 {
+                        let value = store.get(format!("key{}", key_id))?.expect("key missing");
+                        assert!(value.parse::<u64>().expect("value is not a number") < 100);
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    writer.join().expect("writer thread panicked")?;
+    for reader in readers {
+        reader.join().expect("reader thread panicked")?;
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Reads racing compactions that remove the segments they point into should find the moved value instead of failing
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        compaction_threshold: u64::MAX,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+
+    let compactor = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..50 {
+                store.set("key0".to_owned(), "value".to_owned())?;
+                store.compact()?;
+            }
+            Ok(())
+        })
+    };
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    for key_id in 0..100 {
+                        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    compactor.join().expect("compaction thread panicked")?;
+    for reader in readers {
+        reader.join().expect("reader thread panicked")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use kvs::engines::{Durability, KvStore, KvsEngine, Metadata, SledKvsEngine};
+use kvs::error::{KvsError, Result};
+use kvs::utils::{LOG_FORMAT_VERSION, META_FILE_NAME, SLED_FILE_NAME, SLED_FORMAT_VERSION};
+use std::fs;
+use tempfile::TempDir;
+
+// The first open records the engine and its format version, and later opens with the same engine keep the record
+#[test]
+fn metadata_written_on_first_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    drop(KvStore::open(temp_dir.path())?);
+
+    let stored = Metadata::verify(temp_dir.path(), "kvs")?;
+    assert_eq!(stored.engine, "kvs");
+    assert_eq!(stored.format_version, LOG_FORMAT_VERSION);
+
+    drop(KvStore::open(temp_dir.path())?);
+    assert_eq!(Metadata::verify(temp_dir.path(), "kvs")?, stored);
+
+    Ok(())
+}
+
+// Opening a directory with a different engine than the one recorded fails, whichever engine wrote it
+#[test]
+fn metadata_engine_mismatch() -> Result<()> {
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvsEngine::open(sled_dir.path(), Durability::default())?);
+
+    assert_eq!(Metadata::verify(sled_dir.path(), "sled")?.format_version, SLED_FORMAT_VERSION);
+    assert!(sled_dir.path().join(SLED_FILE_NAME).exists());
+    match KvStore::open(sled_dir.path()) {
+        Err(KvsError::EngineMismatch(_)) => {}
+        Err(err) => panic!("expected an engine mismatch, got {}", err),
+        Ok(_) => panic!("kvs opened a sled directory"),
+    }
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(kvs_dir.path())?);
+    match SledKvsEngine::open(kvs_dir.path(), Durability::default()) {
+        Err(KvsError::EngineMismatch(_)) => {}
+        Err(err) => panic!("expected an engine mismatch, got {}", err),
+        Ok(_) => panic!("sled opened a kvs directory"),
+    }
+
+    // Without a META file the engine is recognised by its files
+    fs::remove_file(sled_dir.path().join(META_FILE_NAME))?;
+    assert!(matches!(KvStore::open(sled_dir.path()), Err(KvsError::EngineMismatch(_))));
+
+    Ok(())
+}
+
+// Data written by a newer format version, or an unreadable META file, is refused
+#[test]
+fn metadata_refuses_newer_or_unreadable() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let meta_path = temp_dir.path().join(META_FILE_NAME);
+
+    fs::write(&meta_path, r#"{"engine":"kvs","format_version":99,"created_at":0}"#).unwrap();
+    match Metadata::verify(temp_dir.path(), "kvs") {
+        Err(KvsError::Metadata(_)) => {}
+        other => panic!("expected a metadata error, got {:?}", other),
+    }
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Metadata(_))));
+
+    fs::write(&meta_path, "not metadata").unwrap();
+    match Metadata::verify(temp_dir.path(), "kvs") {
+        Err(KvsError::Metadata(_)) => {}
+        other => panic!("expected a metadata error, got {:?}", other),
+    }
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path(), Durability::default()),
+        Err(KvsError::Metadata(_))
+    ));
+}
+
+// A directory written before the META file existed is recognised by its files and upgraded on open
+#[test]
+fn metadata_upgrades_legacy_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log.txt"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path(), Durability::default()),
+        Err(KvsError::EngineMismatch(_))
+    ));
+    assert_eq!(Metadata::verify(temp_dir.path(), "kvs")?.format_version, 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert_eq!(Metadata::verify(temp_dir.path(), "kvs")?.format_version, LOG_FORMAT_VERSION);
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use kvs::error::{KvsError, Result};
+use kvs::protocol::{
+    read_frame, read_frame_async, write_frame, write_frame_async, ErrorKind as ResponseErrorKind, Request, Response,
+};
+use std::io::{Cursor, ErrorKind};
+
+// Messages survive a round trip through frames whatever bytes their keys and values hold
+#[test]
+fn frame_round_trip() -> Result<()> {
+    let messages = [
+        Request::Set {
+            key: b"key\n1".to_vec(),
+            value: vec![0xff; 300_000],
+        },
+        Request::Get { key: Vec::new() },
+        Request::Remove { key: b"+OK\n".to_vec() },
+    ];
+
+    let mut wire = Vec::new();
+    for message in messages.iter() {
+        write_frame(&mut wire, message)?;
+    }
+
+    let mut reader = Cursor::new(wire);
+    for message in messages.iter() {
+        assert_eq!(&read_frame::<Request>(&mut reader)?, message);
+    }
+
+    Ok(())
+}
+
+// A frame cut short by a closed connection is an error rather than a partial message
+#[test]
+fn truncated_frame() -> Result<()> {
+    let mut wire = Vec::new();
+    write_frame(&mut wire, &Response::Value(Some(b"value1".to_vec())))?;
+    wire.truncate(wire.len() - 1);
+
+    match read_frame::<Response>(&mut Cursor::new(wire)) {
+        Err(KvsError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => {}
+        other => panic!("expected an unexpected end of file, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Frames written by the blocking side are read by the async side and the other way round
+#[tokio::test]
+async fn async_frames_match_blocking_frames() -> Result<()> {
+    let response = Response::from_error(&KvsError::KeyNotFound);
+
+    let mut wire = Vec::new();
+    write_frame(&mut wire, &response)?;
+    assert_eq!(read_frame_async::<Response>(&mut wire.as_slice()).await?, response);
+
+    let mut async_wire = Vec::new();
+    write_frame_async(&mut async_wire, &response).await?;
+    assert_eq!(async_wire, wire);
+
+    Ok(())
+}
+
+// Errors keep their kind across the wire, so a missing key is told apart from a failing server
+#[test]
+fn error_responses_round_trip() {
+    let response = Response::from_error(&KvsError::KeyNotFound);
+    assert!(matches!(
+        response,
+        Response::Err {
+            kind: ResponseErrorKind::NotFound,
+            ..
+        }
+    ));
+    assert!(matches!(response.into_result(), Err(KvsError::KeyNotFound)));
+
+    let response = Response::from_error(&KvsError::CommandError("Command unrecognized".to_owned()));
+    assert!(matches!(response.into_result(), Err(KvsError::CommandError(_))));
+
+    let response = Response::from_error(&KvsError::Io(ErrorKind::PermissionDenied.into()));
+    assert!(matches!(response.into_result(), Err(KvsError::Io(_))));
+
+    let response = Response::from_error(&KvsError::Corruption("bad checksum".to_owned()));
+    assert!(matches!(response.into_result(), Err(KvsError::Store(_))));
+
+    assert_eq!(Response::Ok.into_result().ok(), Some(Response::Ok));
+}
@@ -0,0 +1,156 @@
+use kvs::engines::{CasOutcome, WriteBatch};
+use kvs::error::{KvsError, Result};
+use kvs::server::{KvsServer, ServerOptions};
+use kvs::KvsClient;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A client sends any number of requests over a single connection, and several clients do so at once
+#[test]
+fn persistent_connections() -> Result<()> {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = ServerOptions {
+        data_dir: temp_dir.path().to_owned(),
+        threads: 4,
+        ..Default::default()
+    };
+
+    thread::spawn(move || KvsServer::route_request(addr.to_owned(), "kvs".to_owned(), options));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut first = KvsClient::connect(addr.to_owned())?;
+    let mut second = KvsClient::connect(addr.to_owned())?;
+
+    for key_id in 0..100 {
+        first.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        assert_eq!(second.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    first.remove("key1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, None);
+    assert!(matches!(second.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+
+    // The connection stays usable after a failed request
+    assert_eq!(second.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// More clients than the pool has threads can keep their connections open and all be answered
+#[test]
+fn more_persistent_connections_than_threads() -> Result<()> {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let threads = 2;
+    let options = ServerOptions {
+        data_dir: temp_dir.path().to_owned(),
+        threads,
+        ..Default::default()
+    };
+
+    thread::spawn(move || KvsServer::route_request(addr.to_owned(), "kvs".to_owned(), options));
+    thread::sleep(Duration::from_secs(1));
+
+    let clients = (0..=threads)
+        .map(|_| KvsClient::connect(addr.to_owned()))
+        .collect::<Result<Vec<_>>>()?;
+
+    // A starved client would wait forever, so the clients are used on a thread of their own
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(take_turns(clients)));
+
+    receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("a client was never answered")
+}
+
+// Connections beyond the limit are closed, and a closed connection makes room for another
+#[test]
+fn connections_beyond_limit_are_closed() -> Result<()> {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = ServerOptions {
+        data_dir: temp_dir.path().to_owned(),
+        max_connections: 1,
+        ..Default::default()
+    };
+
+    thread::spawn(move || KvsServer::route_request(addr.to_owned(), "kvs".to_owned(), options));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut first = KvsClient::connect(addr.to_owned())?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut refused = KvsClient::connect(addr.to_owned())?;
+    assert!(refused.get("key1".to_owned()).is_err());
+
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+
+    let mut second = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Send requests from every client in turn, each on its own open connection
+fn take_turns(mut clients: Vec<KvsClient>) -> Result<()> {
+    for round in 0..10 {
+        for (client_id, client) in clients.iter_mut().enumerate() {
+            client.set(format!("key{}", client_id), format!("value{}", round))?;
+        }
+    }
+
+    for (client_id, client) in clients.iter_mut().enumerate() {
+        assert_eq!(client.get(format!("key{}", client_id))?, Some("value9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A batch sent over the wire is applied as a whole, and a batch removing a missing key still succeeds.
+// Compare-and-swap requests are answered with whether they swapped
+#[test]
+fn batch_and_cas_requests() -> Result<()> {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = ServerOptions {
+        data_dir: temp_dir.path().to_owned(),
+        ..Default::default()
+    };
+
+    thread::spawn(move || KvsServer::route_request(addr.to_owned(), "sled".to_owned(), options));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.remove(b"key1".to_vec());
+    batch.remove(b"missing".to_vec());
+    client.apply_batch(batch)?;
+
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // A compare-and-swap that does not match reports the value the key holds
+    assert_eq!(
+        client.compare_and_swap(b"key2".to_vec(), None, Some(b"value4".to_vec()))?,
+        CasOutcome::Mismatch {
+            current: Some(b"value2".to_vec())
+        }
+    );
+    assert_eq!(
+        client.compare_and_swap(b"key2".to_vec(), Some(b"value2".to_vec()), Some(b"value4".to_vec()))?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(client.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
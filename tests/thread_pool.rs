@@ -0,0 +1,74 @@
+use kvs::error::{KvsError, Result};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+const TASK_COUNT: usize = 20;
+
+// Run TASK_COUNT jobs on a pool and wait for every one of them to finish
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    for _ in 0..TASK_COUNT {
+        let counter = Arc::clone(&counter);
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+
+    for _ in 0..TASK_COUNT {
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("job did not finish");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_COUNT);
+
+    Ok(())
+}
+
+// Jobs that panic should not stop the pool from running the jobs after them
+fn spawn_panic_task<P: ThreadPool>(pool: P) -> Result<()> {
+    for _ in 0..TASK_COUNT {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?)
+}
+
+// A pool with no threads would never run its jobs, so it cannot be created
+#[test]
+fn thread_pools_need_threads() {
+    assert!(matches!(NaiveThreadPool::new(0), Err(KvsError::ThreadPoolError(_))));
+    assert!(matches!(SharedQueueThreadPool::new(0), Err(KvsError::ThreadPoolError(_))));
+    assert!(matches!(RayonThreadPool::new(0), Err(KvsError::ThreadPoolError(_))));
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task(RayonThreadPool::new(4)?)
+}